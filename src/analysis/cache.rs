@@ -0,0 +1,269 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use solhat::context::ProcessContext;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+use crate::analysis::quality::{AnalysisRegion, QualityMetric, RegionCombine};
+use crate::analysis::sigma::FrameAnalysis;
+use crate::calibration::{file_stamp, CombineMethod};
+use crate::state::{solhat_config_dir, ParametersState};
+
+///////////////////////////////////////////////////////
+/// Sigma Analysis Cache
+///////////////////////////////////////////////////////
+
+/// Changed whenever the cached values would be computed differently
+const CACHE_VERSION: u32 = 2;
+
+/// A calibration input the frames were calibrated with before analysis
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+struct CalibrationInput {
+    path: String,
+    /// How the master is combined. The hot pixel map has none.
+    method: Option<CombineMethod>,
+    file_size: u64,
+    modified: u64,
+}
+
+impl CalibrationInput {
+    fn new(path: &Option<PathBuf>, method: Option<CombineMethod>) -> Option<Self> {
+        let path = path.as_ref()?.to_string_lossy().to_string();
+        // An unreadable input fails the calibration before any frame is analyzed
+        let (file_size, modified) = file_stamp(Path::new(&path)).unwrap_or_default();
+        Some(CalibrationInput {
+            path,
+            method,
+            file_size,
+            modified,
+        })
+    }
+}
+
+/// The analysis parameters the cached results depend on
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+struct AnalysisSettings {
+    window_size: usize,
+    obj_detection_threshold: f64,
+    #[serde(default)]
+    metric: QualityMetric,
+    #[serde(default)]
+    regions: Vec<AnalysisRegion>,
+    #[serde(default)]
+    region_combine: RegionCombine,
+    #[serde(default)]
+    calibration: Vec<CalibrationInput>,
+}
+
+impl AnalysisSettings {
+    fn from_parameters(params: &ParametersState) -> Self {
+        AnalysisSettings {
+            window_size: params.analysis_window_size,
+            obj_detection_threshold: params.obj_detection_threshold,
+            metric: params.quality_metric,
+            regions: params.analysis_regions.clone(),
+            region_combine: params.region_combine,
+            calibration: [
+                CalibrationInput::new(&params.dark, Some(params.dark_method)),
+                CalibrationInput::new(&params.flat, Some(params.flat_method)),
+                CalibrationInput::new(&params.darkflat, Some(params.darkflat_method)),
+                CalibrationInput::new(&params.bias, Some(params.bias_method)),
+                CalibrationInput::new(&params.hot_pixel_map, None),
+            ]
+            .into_iter()
+            .flatten()
+            .collect(),
+        }
+    }
+}
+
+/// Everything the cached results depend on. The results are only reused when all of
+/// it matches.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+struct CacheKey {
+    version: u32,
+    source_file: String,
+    file_size: u64,
+    /// Modification time of the file, in nanoseconds since the epoch
+    modified: u64,
+    #[serde(flatten)]
+    settings: AnalysisSettings,
+}
+
+impl CacheKey {
+    fn new(source_file: &str, settings: &AnalysisSettings) -> Result<Self> {
+        let (file_size, modified) = file_stamp(Path::new(source_file))?;
+        Ok(CacheKey {
+            version: CACHE_VERSION,
+            source_file: source_file.to_owned(),
+            file_size,
+            modified,
+            settings: settings.clone(),
+        })
+    }
+
+    /// Each SER file has one cache file, replaced when the key changes
+    fn cache_file_path(&self) -> Result<PathBuf> {
+        let mut hasher = DefaultHasher::new();
+        self.source_file.hash(&mut hasher);
+        Ok(cache_dir()?.join(format!("{:016x}.json", hasher.finish())))
+    }
+}
+
+/// Center of mass offset and sigma of a single frame
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct CachedFrame {
+    pub sigma: f64,
+    pub offset_h: f64,
+    pub offset_v: f64,
+    /// Sigma within each of the analysis windows placed by the user
+    #[serde(default)]
+    pub region_sigmas: Vec<f64>,
+}
+
+impl CachedFrame {
+    fn from_frame_analysis(analysis: &FrameAnalysis) -> Self {
+        let fr = &analysis.frame_record;
+        CachedFrame {
+            sigma: fr.sigma,
+            offset_h: fr.offset.h as f64,
+            offset_v: fr.offset.v as f64,
+            region_sigmas: analysis.region_sigmas.clone(),
+        }
+    }
+
+    pub fn apply_to(&self, analysis: &mut FrameAnalysis) {
+        let fr = &mut analysis.frame_record;
+        fr.sigma = self.sigma;
+        fr.offset.h = self.offset_h as _;
+        fr.offset.v = self.offset_v as _;
+        analysis.region_sigmas = self.region_sigmas.clone();
+    }
+}
+
+/// The analysis of every frame in one SER file, written as JSON in `~/.solhat/cache/`
+#[derive(Deserialize, Serialize)]
+struct CachedAnalysis {
+    key: CacheKey,
+    /// Indexed by frame id
+    frames: Vec<CachedFrame>,
+}
+
+fn cache_dir() -> Result<PathBuf> {
+    let dir = solhat_config_dir()?.join("cache");
+    if !dir.exists() {
+        fs::create_dir(&dir)?;
+    }
+    Ok(dir)
+}
+
+/// Cached analysis results for the light inputs of a process context
+pub struct AnalysisCache {
+    settings: AnalysisSettings,
+    /// Cached frames by source file id
+    files: HashMap<String, Vec<CachedFrame>>,
+}
+
+impl AnalysisCache {
+    /// Loads the cached results for each light input that has them and is unchanged
+    pub fn load(context: &ProcessContext, params: &ParametersState) -> Self {
+        let mut cache = AnalysisCache {
+            settings: AnalysisSettings::from_parameters(params),
+            files: HashMap::new(),
+        };
+
+        let mut source_files: Vec<&String> = context
+            .frame_records
+            .iter()
+            .map(|fr| &fr.source_file_id)
+            .collect();
+        source_files.sort();
+        source_files.dedup();
+
+        source_files.into_iter().for_each(|source_file| {
+            match load_cached_analysis(source_file, &cache.settings) {
+                Ok(Some(frames)) => {
+                    info!("Using cached analysis for {}", source_file);
+                    cache.files.insert(source_file.to_owned(), frames);
+                }
+                Ok(None) => {}
+                Err(why) => warn!(
+                    "Unable to read analysis cache for {}: {:?}",
+                    source_file, why
+                ),
+            }
+        });
+        cache
+    }
+
+    pub fn get(&self, source_file_id: &str, frame_id: usize) -> Option<&CachedFrame> {
+        self.files.get(source_file_id)?.get(frame_id)
+    }
+
+    /// Saves the results for each source file that wasn't already cached
+    pub fn save(&self, analyses: &[FrameAnalysis]) {
+        let mut by_file: HashMap<&String, Vec<Option<CachedFrame>>> = HashMap::new();
+        analyses
+            .iter()
+            .filter(|a| !self.files.contains_key(&a.frame_record.source_file_id))
+            .for_each(|a| {
+                let fr = &a.frame_record;
+                let frames = by_file.entry(&fr.source_file_id).or_default();
+                if frames.len() <= fr.frame_id {
+                    frames.resize(fr.frame_id + 1, None);
+                }
+                frames[fr.frame_id] = Some(CachedFrame::from_frame_analysis(a));
+            });
+
+        by_file.into_iter().for_each(|(source_file, frames)| {
+            // Only complete analyses are cached
+            let frames: Option<Vec<CachedFrame>> = frames.into_iter().collect();
+            if let Some(frames) = frames {
+                if let Err(why) = save_cached_analysis(source_file, &self.settings, frames) {
+                    warn!(
+                        "Unable to write analysis cache for {}: {:?}",
+                        source_file, why
+                    );
+                }
+            }
+        });
+    }
+}
+
+fn load_cached_analysis(
+    source_file: &str,
+    settings: &AnalysisSettings,
+) -> Result<Option<Vec<CachedFrame>>> {
+    let key = CacheKey::new(source_file, settings)?;
+    let cache_file_path = key.cache_file_path()?;
+    if !cache_file_path.exists() {
+        return Ok(None);
+    }
+    let t = fs::read_to_string(&cache_file_path)?;
+    let cached: CachedAnalysis = serde_json::from_str(&t)?;
+    if cached.key == key {
+        Ok(Some(cached.frames))
+    } else {
+        debug!("Analysis cache for {} is out of date", source_file);
+        Ok(None)
+    }
+}
+
+fn save_cached_analysis(
+    source_file: &str,
+    settings: &AnalysisSettings,
+    frames: Vec<CachedFrame>,
+) -> Result<()> {
+    let key = CacheKey::new(source_file, settings)?;
+    let cache_file_path = key.cache_file_path()?;
+    info!(
+        "Writing analysis cache for {} to {:?}",
+        source_file, cache_file_path
+    );
+    let json_str = serde_json::to_string(&CachedAnalysis { key, frames })?;
+    fs::write(cache_file_path, json_str)?;
+    Ok(())
+}
@@ -0,0 +1,511 @@
+use anyhow::Result;
+use gtk::gdk::prelude::GdkCairoContextExt;
+use gtk::gdk_pixbuf::{Pixbuf, PixbufLoader};
+use gtk::glib;
+use gtk::prelude::*;
+use gtk::{
+    Button, DrawingArea, EventControllerMotion, EventSequenceState, GestureClick, GestureDrag,
+    Label, Orientation,
+};
+use std::cell::RefCell;
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::analysis::sigma::{create_chart, AnalysisSeries, CHART_MARGINS};
+use crate::state::{ParametersState, STATE};
+
+///////////////////////////////////////////////////////
+/// Interactive Analysis Chart
+///////////////////////////////////////////////////////
+
+/// How far from a point, in screen pixels, the cursor can be to pick it
+const PICK_DISTANCE: f64 = 12.0;
+
+/// How far above or below a limit line, in screen pixels, it can be grabbed
+const LINE_PICK_DISTANCE: f64 = 5.0;
+
+/// Half the size of the marker drawn on excluded frames
+const MARKER_SIZE: f64 = 4.0;
+
+/// The frame limits drawn as lines across the chart
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum LimitLine {
+    MinSigma,
+    MaxSigma,
+    TopPercentage,
+}
+
+impl LimitLine {
+    fn color(&self) -> (f64, f64, f64) {
+        match self {
+            LimitLine::MinSigma | LimitLine::MaxSigma => (0.18, 0.55, 0.34),
+            LimitLine::TopPercentage => (0.9, 0.55, 0.0),
+        }
+    }
+
+    fn label(&self, params: &ParametersState) -> String {
+        match self {
+            LimitLine::MinSigma => format!("Min Sigma {:.1}", params.min_sigma),
+            LimitLine::MaxSigma => format!("Max Sigma {:.1}", params.max_sigma),
+            LimitLine::TopPercentage => format!("Top {}%", params.top_percentage),
+        }
+    }
+}
+
+struct ChartState {
+    series: Option<AnalysisSeries>,
+    /// The rendered chart and the size it was rendered at
+    pixbuf: Option<(Pixbuf, i32, i32)>,
+    /// Index into the series of the frame under the cursor
+    hover: Option<usize>,
+    /// The limit line being dragged, and where the drag started
+    dragging: Option<(LimitLine, f64)>,
+}
+
+impl ChartState {
+    /// The bounds of the plot area within a chart of the given size
+    fn plot_area(width: f64, height: f64) -> (f64, f64, f64, f64) {
+        let (top, right, bottom, left) = CHART_MARGINS;
+        (
+            left as f64,
+            top as f64,
+            width - (left + right) as f64,
+            height - (top + bottom) as f64,
+        )
+    }
+
+    /// Screen y of a sigma value, which is off the plot area if the sigma is outside
+    /// the range of the series
+    fn sigma_to_y(&self, sigma: f64, width: f64, height: f64) -> Option<f64> {
+        let range = self.series.as_ref()?.minmax();
+        let (_, top, _, plot_height) = Self::plot_area(width, height);
+        let y_fraction = if range.max > range.min {
+            (range.max - sigma) / (range.max - range.min)
+        } else {
+            0.5
+        };
+        Some(top + y_fraction * plot_height)
+    }
+
+    /// Sigma at a screen y, limited to the range of the series
+    fn y_to_sigma(&self, y: f64, width: f64, height: f64) -> Option<f64> {
+        let range = self.series.as_ref()?.minmax();
+        let (_, top, _, plot_height) = Self::plot_area(width, height);
+        let y_fraction = ((y - top) / plot_height).clamp(0.0, 1.0);
+        Some(range.max - y_fraction * (range.max - range.min))
+    }
+
+    /// Screen position of the frame at `index` in the per-frame series
+    fn point_position(&self, index: usize, width: f64, height: f64) -> Option<(f64, f64)> {
+        let series = self.series.as_ref()?;
+        let frame = series.frames.get(index)?;
+        let (left, _, plot_width, _) = Self::plot_area(width, height);
+        Some((
+            left + index as f64 / series.frames.len() as f64 * plot_width,
+            self.sigma_to_y(frame.sigma, width, height)?,
+        ))
+    }
+
+    /// Screen y of each limit line. Limits outside the range of the series are drawn
+    /// at the edge of the plot area.
+    fn limit_lines(
+        &self,
+        params: &ParametersState,
+        width: f64,
+        height: f64,
+    ) -> Vec<(LimitLine, f64)> {
+        let series = match &self.series {
+            Some(series) => series,
+            None => return vec![],
+        };
+        let (_, top, _, plot_height) = Self::plot_area(width, height);
+        [
+            (LimitLine::MaxSigma, Some(params.max_sigma)),
+            (LimitLine::MinSigma, Some(params.min_sigma)),
+            (
+                LimitLine::TopPercentage,
+                series.top_percentage_cutoff(params),
+            ),
+        ]
+        .iter()
+        .filter_map(|(line, sigma)| {
+            let y = self.sigma_to_y((*sigma)?, width, height)?;
+            Some((*line, y.clamp(top, top + plot_height)))
+        })
+        .collect()
+    }
+
+    /// The limit line nearest the cursor, if it's close enough to grab
+    fn pick_line(
+        &self,
+        params: &ParametersState,
+        y: f64,
+        width: f64,
+        height: f64,
+    ) -> Option<(LimitLine, f64)> {
+        self.limit_lines(params, width, height)
+            .into_iter()
+            .filter(|(_, line_y)| (line_y - y).abs() <= LINE_PICK_DISTANCE)
+            .min_by(|a, b| (a.1 - y).abs().total_cmp(&(b.1 - y).abs()))
+    }
+
+    /// The frame nearest the cursor, if it's close enough to pick
+    fn pick(&self, x: f64, y: f64, width: f64, height: f64) -> Option<usize> {
+        let series = self.series.as_ref()?;
+        if series.frames.is_empty() {
+            return None;
+        }
+        let (left, _, plot_width, _) = Self::plot_area(width, height);
+        let index = ((x - left) / plot_width * series.frames.len() as f64).round();
+        let index = index.clamp(0.0, (series.frames.len() - 1) as f64) as usize;
+        let (px, py) = self.point_position(index, width, height)?;
+        if (px - x).abs() <= PICK_DISTANCE && (py - y).abs() <= PICK_DISTANCE {
+            Some(index)
+        } else {
+            None
+        }
+    }
+}
+
+/// The sigma analysis chart. Hovering a point shows its frame and sigma, and clicking
+/// it excludes the frame from stacking, or includes it again. The min/max sigma and
+/// top percentage are drawn as lines which can be dragged to change them.
+#[derive(Clone)]
+pub struct AnalysisChart {
+    area: DrawingArea,
+    lbl_readout: Label,
+    lbl_excluded: Label,
+    lbl_surviving: Label,
+    state: Rc<RefCell<ChartState>>,
+    /// Called after a limit has been dragged to a new value
+    on_limits_changed: Rc<dyn Fn()>,
+}
+
+impl AnalysisChart {
+    pub fn new<F: Fn() + 'static>(container: &gtk::Box, on_limits_changed: F) -> Self {
+        let state = Rc::new(RefCell::new(ChartState {
+            series: None,
+            pixbuf: None,
+            hover: None,
+            dragging: None,
+        }));
+
+        let toolbar = gtk::Box::new(Orientation::Horizontal, 2);
+        let lbl_readout = Label::new(None);
+        lbl_readout.set_hexpand(true);
+        lbl_readout.set_xalign(0.0);
+        let lbl_excluded = Label::new(None);
+        let lbl_surviving = Label::new(None);
+        let btn_clear = Button::with_label("Include All");
+        btn_clear.set_tooltip_text(Some("Include all frames excluded by hand"));
+        toolbar.append(&lbl_readout);
+        toolbar.append(&lbl_excluded);
+        toolbar.append(&lbl_surviving);
+        toolbar.append(&btn_clear);
+
+        let area = DrawingArea::new();
+        area.set_hexpand(true);
+        area.set_vexpand(true);
+
+        container.append(&area);
+        container.append(&toolbar);
+
+        area.set_draw_func(glib::clone!(@strong state => move |_, cr, width, height| {
+            let mut state = state.borrow_mut();
+            let rendered = match (&state.series, &state.pixbuf) {
+                (None, _) => return,
+                (Some(_), Some((_, w, h))) => *w == width && *h == height,
+                (Some(_), None) => false,
+            };
+
+            // The chart is rendered again when the view is resized, so it stays sharp
+            if !rendered {
+                let pixbuf = render_chart(state.series.as_ref().unwrap(), width, height);
+                match pixbuf {
+                    Ok(pixbuf) => state.pixbuf = Some((pixbuf, width, height)),
+                    Err(why) => {
+                        error!("Failed to render analysis chart: {:?}", why);
+                        return;
+                    }
+                }
+            }
+            if let Some((pixbuf, _, _)) = &state.pixbuf {
+                cr.set_source_pixbuf(pixbuf, 0.0, 0.0);
+                if let Err(why) = cr.paint() {
+                    error!("Failed to draw analysis chart: {:?}", why);
+                }
+            }
+
+            let (width, height) = (width as f64, height as f64);
+            let params = STATE.lock().unwrap().params.clone();
+            cr.set_source_rgb(0.9, 0.1, 0.1);
+            cr.set_line_width(1.5);
+            state
+                .series
+                .iter()
+                .flat_map(|s| s.frames.iter().enumerate())
+                .filter(|(_, f)| params.is_frame_excluded(&f.source_file_id, f.frame_id))
+                .filter_map(|(i, _)| state.point_position(i, width, height))
+                .for_each(|(x, y)| {
+                    cr.move_to(x - MARKER_SIZE, y - MARKER_SIZE);
+                    cr.line_to(x + MARKER_SIZE, y + MARKER_SIZE);
+                    cr.move_to(x + MARKER_SIZE, y - MARKER_SIZE);
+                    cr.line_to(x - MARKER_SIZE, y + MARKER_SIZE);
+                });
+            if let Err(why) = cr.stroke() {
+                error!("Failed to draw excluded frames: {:?}", why);
+            }
+
+            let (left, _, plot_width, _) = ChartState::plot_area(width, height);
+            state
+                .limit_lines(&params, width, height)
+                .iter()
+                .for_each(|(line, y)| {
+                    let (r, g, b) = line.color();
+                    cr.set_source_rgb(r, g, b);
+                    cr.set_line_width(if state.dragging.map(|d| d.0) == Some(*line) {
+                        2.5
+                    } else {
+                        1.5
+                    });
+                    cr.move_to(left, *y);
+                    cr.line_to(left + plot_width, *y);
+                    if let Err(why) = cr.stroke() {
+                        error!("Failed to draw limit line: {:?}", why);
+                    }
+                    cr.move_to(left + 4.0, y - 4.0);
+                    if let Err(why) = cr.show_text(&line.label(&params)) {
+                        error!("Failed to label limit line: {:?}", why);
+                    }
+                });
+
+            if let Some((x, y)) = state
+                .hover
+                .and_then(|i| state.point_position(i, width, height))
+            {
+                cr.set_source_rgb(0.0, 0.4, 1.0);
+                cr.arc(x, y, MARKER_SIZE + 1.0, 0.0, 2.0 * std::f64::consts::PI);
+                if let Err(why) = cr.stroke() {
+                    error!("Failed to draw hovered frame: {:?}", why);
+                }
+            }
+        }));
+
+        let chart = AnalysisChart {
+            area: area.clone(),
+            lbl_readout,
+            lbl_excluded,
+            lbl_surviving,
+            state,
+            on_limits_changed: Rc::new(on_limits_changed),
+        };
+
+        let motion = EventControllerMotion::new();
+        motion.connect_motion(glib::clone!(@strong chart => move |_, x, y| {
+            chart.set_hover(x, y);
+        }));
+        motion.connect_leave(glib::clone!(@strong chart => move |_| {
+            chart.state.borrow_mut().hover = None;
+            chart.lbl_readout.set_label("");
+            chart.area.queue_draw();
+        }));
+        area.add_controller(motion);
+
+        let click = GestureClick::new();
+        click.connect_released(glib::clone!(@strong chart => move |_, _, x, y| {
+            chart.set_hover(x, y);
+            chart.toggle_hovered_frame();
+        }));
+        area.add_controller(click);
+
+        // Frames take priority over the limit lines, so a line can only be grabbed
+        // where there's no frame to click. Grabbing a line claims the press, so
+        // releasing it over a frame doesn't exclude that frame.
+        let drag = GestureDrag::new();
+        drag.connect_drag_begin(glib::clone!(@strong chart => move |gesture, x, y| {
+            let width = chart.area.width() as f64;
+            let height = chart.area.height() as f64;
+            let params = STATE.lock().unwrap().params.clone();
+            let grabbed = {
+                let mut state = chart.state.borrow_mut();
+                if state.pick(x, y, width, height).is_none() {
+                    state.dragging = state.pick_line(&params, y, width, height);
+                }
+                state.dragging.is_some()
+            };
+            if grabbed {
+                gesture.set_state(EventSequenceState::Claimed);
+            }
+        }));
+        drag.connect_drag_update(glib::clone!(@strong chart => move |_, _, offset_y| {
+            chart.drag_line(offset_y);
+        }));
+        drag.connect_drag_end(glib::clone!(@strong chart => move |_, _, _| {
+            chart.state.borrow_mut().dragging = None;
+            chart.area.queue_draw();
+        }));
+        area.add_controller(drag);
+
+        // The exclusions may have changed with the light inputs while it was hidden
+        area.connect_map(glib::clone!(@strong chart => move |_| {
+            chart.refresh();
+        }));
+
+        btn_clear.connect_clicked(glib::clone!(@strong chart => move |_| {
+            STATE.lock().unwrap().params.excluded_frames.clear();
+            chart.refresh();
+        }));
+
+        chart.refresh();
+        chart
+    }
+
+    /// Shows the results of a sigma analysis
+    pub fn set_series(&self, series: AnalysisSeries) {
+        {
+            let mut state = self.state.borrow_mut();
+            state.series = Some(series);
+            state.pixbuf = None;
+            state.hover = None;
+        }
+        self.refresh();
+    }
+
+    /// Redraws the chart and counts the exclusions again, such as after the parameters
+    /// have been loaded from elsewhere
+    pub fn refresh(&self) {
+        let excluded = STATE.lock().unwrap().params.excluded_frames.len();
+        self.lbl_excluded.set_label(&match excluded {
+            0 => "".to_owned(),
+            1 => "1 frame excluded".to_owned(),
+            n => format!("{} frames excluded", n),
+        });
+        let surviving = self.state.borrow().series.as_ref().map(|series| {
+            let params = STATE.lock().unwrap().params.clone();
+            (series.surviving_frames(&params), series.frames.len())
+        });
+        self.lbl_surviving.set_label(&match surviving {
+            Some((surviving, total)) => {
+                format!("{} of {} frames will be stacked", surviving, total)
+            }
+            None => "".to_owned(),
+        });
+        self.update_readout();
+        self.area.queue_draw();
+    }
+
+    fn set_hover(&self, x: f64, y: f64) {
+        let width = self.area.width() as f64;
+        let height = self.area.height() as f64;
+        let hover = self.state.borrow().pick(x, y, width, height);
+        if hover != self.state.borrow().hover {
+            self.state.borrow_mut().hover = hover;
+            self.update_readout();
+            self.area.queue_draw();
+        }
+    }
+
+    /// Moves the limit line being dragged to `offset_y` from where the drag started
+    fn drag_line(&self, offset_y: f64) {
+        let width = self.area.width() as f64;
+        let height = self.area.height() as f64;
+        {
+            let state = self.state.borrow();
+            let (line, start_y) = match state.dragging {
+                Some(dragging) => dragging,
+                None => return,
+            };
+            let (sigma, series) = match (
+                state.y_to_sigma(start_y + offset_y, width, height),
+                &state.series,
+            ) {
+                (Some(sigma), Some(series)) => (sigma, series),
+                _ => return,
+            };
+
+            // Rounded to the precision of the spinners
+            let sigma = (sigma * 10.0).round() / 10.0;
+            let mut app_state = STATE.lock().unwrap();
+            let params = &mut app_state.params;
+            match line {
+                LimitLine::MinSigma => params.min_sigma = sigma.min(params.max_sigma),
+                LimitLine::MaxSigma => params.max_sigma = sigma.max(params.min_sigma),
+                LimitLine::TopPercentage => {
+                    params.top_percentage = series.top_percentage_for_cutoff(params, sigma)
+                }
+            }
+        }
+        (self.on_limits_changed)();
+        self.refresh();
+    }
+
+    fn toggle_hovered_frame(&self) {
+        let frame = {
+            let state = self.state.borrow();
+            match (&state.series, state.hover) {
+                (Some(series), Some(index)) => series.frames[index].clone(),
+                _ => return,
+            }
+        };
+        let excluded = STATE
+            .lock()
+            .unwrap()
+            .params
+            .toggle_frame_exclusion(&frame.source_file_id, frame.frame_id);
+        info!(
+            "Frame {} of {} {}",
+            frame.frame_id,
+            frame.source_file_id,
+            if excluded { "excluded" } else { "included" }
+        );
+        self.refresh();
+    }
+
+    fn update_readout(&self) {
+        let state = self.state.borrow();
+        let readout = match (&state.series, state.hover) {
+            (Some(series), Some(index)) => {
+                let frame = &series.frames[index];
+                let excluded = STATE
+                    .lock()
+                    .unwrap()
+                    .params
+                    .is_frame_excluded(&frame.source_file_id, frame.frame_id);
+                let windows: Vec<String> = frame
+                    .region_sigmas
+                    .iter()
+                    .enumerate()
+                    .map(|(i, s)| format!("{}: {:.3}", i + 1, s))
+                    .collect();
+                format!(
+                    "Frame {} of {}  sigma: {:.3}{}{}",
+                    frame.frame_id,
+                    Path::new(&frame.source_file_id)
+                        .file_name()
+                        .map(|f| f.to_string_lossy().to_string())
+                        .unwrap_or_else(|| frame.source_file_id.to_owned()),
+                    frame.sigma,
+                    if windows.is_empty() {
+                        "".to_owned()
+                    } else {
+                        format!("  windows {}", windows.join(", "))
+                    },
+                    if excluded { "  (excluded)" } else { "" }
+                )
+            }
+            _ => "".to_owned(),
+        };
+        self.lbl_readout.set_label(&readout);
+    }
+}
+
+/// Renders the chart to a pixbuf of the given size
+fn render_chart(series: &AnalysisSeries, width: i32, height: i32) -> Result<Pixbuf> {
+    let svg_string = create_chart(series, width as isize, height as isize)?;
+    let loader = PixbufLoader::new();
+    loader.write(svg_string.as_bytes())?;
+    loader.close()?;
+    loader
+        .pixbuf()
+        .ok_or_else(|| anyhow!("SVG loader produced no image"))
+}
@@ -1,2 +1,5 @@
+pub mod cache;
+pub mod chart;
+pub mod quality;
 pub mod sigma;
 pub mod threshold;
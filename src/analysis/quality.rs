@@ -0,0 +1,250 @@
+use itertools::iproduct;
+use sciimg::prelude::*;
+use sciimg::quality;
+use serde::{Deserialize, Serialize};
+
+///////////////////////////////////////////////////////
+/// Frame Quality Metrics
+///////////////////////////////////////////////////////
+
+/// Number of patches across and down the frame for local contrast
+const CONTRAST_PATCH_GRID: usize = 4;
+
+/// How a frame's sharpness is measured. Higher values are better for each of them, but
+/// their scales differ, so sigma limits need to be set again after changing it.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum QualityMetric {
+    /// SciImg's quality estimation on a single window at the disk center
+    #[default]
+    PointEstimation,
+    /// Variance of the Laplacian over the window at the disk center
+    LaplacianVariance,
+    /// Mean squared Sobel gradient magnitude over the window at the disk center
+    Tenengrad,
+    /// Mean RMS contrast of patches spread across the frame, skipping those darker
+    /// than the object detection threshold. Suits limb and prominence captures where
+    /// the disk center is uniform.
+    LocalContrast,
+}
+
+impl QualityMetric {
+    /// Identifier used by the quality metric selector
+    pub fn id(&self) -> &str {
+        match self {
+            QualityMetric::PointEstimation => "PointEstimation",
+            QualityMetric::LaplacianVariance => "LaplacianVariance",
+            QualityMetric::Tenengrad => "Tenengrad",
+            QualityMetric::LocalContrast => "LocalContrast",
+        }
+    }
+
+    pub fn from_id(id: &str) -> Self {
+        match id {
+            "LaplacianVariance" => QualityMetric::LaplacianVariance,
+            "Tenengrad" => QualityMetric::Tenengrad,
+            "LocalContrast" => QualityMetric::LocalContrast,
+            _ => QualityMetric::PointEstimation,
+        }
+    }
+}
+
+/// How the qualities of several analysis windows are combined into a frame's sigma
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum RegionCombine {
+    #[default]
+    Mean,
+    /// The frame is only as good as its worst window
+    Minimum,
+}
+
+impl RegionCombine {
+    pub fn id(&self) -> &str {
+        match self {
+            RegionCombine::Mean => "Mean",
+            RegionCombine::Minimum => "Minimum",
+        }
+    }
+
+    pub fn from_id(id: &str) -> Self {
+        match id {
+            "Minimum" => RegionCombine::Minimum,
+            _ => RegionCombine::Mean,
+        }
+    }
+
+    pub fn combine(&self, values: &[f64]) -> f64 {
+        match self {
+            RegionCombine::Mean => mean_and_variance(values).0,
+            RegionCombine::Minimum => values.iter().cloned().fold(f64::NAN, f64::min),
+        }
+    }
+}
+
+/// An analysis window placed by the user, such as over a sunspot group or a
+/// prominence. It's kept as an offset from the disk center of mass so it follows the
+/// disk as it drifts through the capture.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct AnalysisRegion {
+    pub offset_x: f64,
+    pub offset_y: f64,
+}
+
+/// A square window of the buffer, limited to its edges
+struct Window {
+    left: usize,
+    top: usize,
+    right: usize,
+    bottom: usize,
+}
+
+impl Window {
+    /// A window of `size` pixels centered on `x`, `y`, shifted to stay inside the buffer
+    /// while leaving a one pixel border for the neighbourhood operators
+    fn centered(buffer: &ImageBuffer, size: usize, x: usize, y: usize) -> Self {
+        let width = size.min(buffer.width.saturating_sub(2)).max(1);
+        let height = size.min(buffer.height.saturating_sub(2)).max(1);
+        let left = x
+            .saturating_sub(width / 2)
+            .clamp(1, buffer.width.saturating_sub(width + 1).max(1));
+        let top = y
+            .saturating_sub(height / 2)
+            .clamp(1, buffer.height.saturating_sub(height + 1).max(1));
+        Window {
+            left,
+            top,
+            right: (left + width).min(buffer.width - 1),
+            bottom: (top + height).min(buffer.height - 1),
+        }
+    }
+
+    fn pixels(&self) -> impl Iterator<Item = (usize, usize)> {
+        iproduct!(self.top..self.bottom, self.left..self.right)
+    }
+}
+
+/// Quality of a frame, from the first band of its buffer. `x` and `y` are the disk
+/// center, as found from the center of mass.
+pub fn frame_quality(
+    metric: QualityMetric,
+    buffer: &ImageBuffer,
+    window_size: usize,
+    x: usize,
+    y: usize,
+    obj_detection_threshold: f64,
+) -> f64 {
+    match metric {
+        QualityMetric::LocalContrast => {
+            local_contrast(buffer, window_size, obj_detection_threshold)
+        }
+        _ => window_quality(metric, buffer, window_size, x, y),
+    }
+}
+
+/// Quality within each of the analysis windows, placed from the disk center at
+/// `center_x`, `center_y`. Local contrast is measured on the window alone rather than
+/// across the frame.
+pub fn region_qualities(
+    metric: QualityMetric,
+    buffer: &ImageBuffer,
+    window_size: usize,
+    center_x: f64,
+    center_y: f64,
+    regions: &[AnalysisRegion],
+) -> Vec<f64> {
+    // Windows partly off the frame are moved back onto it
+    let half = (window_size / 2) as f64;
+    let clamp =
+        |v: f64, size: usize| v.round().clamp(half, (size as f64 - half).max(half)) as usize;
+    regions
+        .iter()
+        .map(|region| {
+            let x = clamp(center_x + region.offset_x, buffer.width);
+            let y = clamp(center_y + region.offset_y, buffer.height);
+            window_quality(metric, buffer, window_size, x, y)
+        })
+        .collect()
+}
+
+fn window_quality(
+    metric: QualityMetric,
+    buffer: &ImageBuffer,
+    window_size: usize,
+    x: usize,
+    y: usize,
+) -> f64 {
+    let window = Window::centered(buffer, window_size, x, y);
+    match metric {
+        QualityMetric::PointEstimation => {
+            quality::get_point_quality_estimation_on_buffer(buffer, window_size, x, y) as f64
+        }
+        QualityMetric::LaplacianVariance => laplacian_variance(buffer, &window),
+        QualityMetric::Tenengrad => tenengrad(buffer, &window),
+        QualityMetric::LocalContrast => rms_contrast(window_mean_and_variance(buffer, &window)),
+    }
+}
+
+fn mean_and_variance(values: &[f64]) -> (f64, f64) {
+    if values.is_empty() {
+        return (0.0, 0.0);
+    }
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / values.len() as f64;
+    (mean, variance)
+}
+
+fn laplacian_variance(buffer: &ImageBuffer, window: &Window) -> f64 {
+    let p = |x: usize, y: usize| buffer.get(x, y) as f64;
+    let laplacian: Vec<f64> = window
+        .pixels()
+        .map(|(y, x)| 4.0 * p(x, y) - p(x - 1, y) - p(x + 1, y) - p(x, y - 1) - p(x, y + 1))
+        .collect();
+    mean_and_variance(&laplacian).1
+}
+
+fn tenengrad(buffer: &ImageBuffer, window: &Window) -> f64 {
+    let p = |x: usize, y: usize| buffer.get(x, y) as f64;
+    let gradients: Vec<f64> = window
+        .pixels()
+        .map(|(y, x)| {
+            let gx = p(x + 1, y - 1) + 2.0 * p(x + 1, y) + p(x + 1, y + 1)
+                - p(x - 1, y - 1)
+                - 2.0 * p(x - 1, y)
+                - p(x - 1, y + 1);
+            let gy = p(x - 1, y + 1) + 2.0 * p(x, y + 1) + p(x + 1, y + 1)
+                - p(x - 1, y - 1)
+                - 2.0 * p(x, y - 1)
+                - p(x + 1, y - 1);
+            gx * gx + gy * gy
+        })
+        .collect();
+    mean_and_variance(&gradients).0
+}
+
+fn window_mean_and_variance(buffer: &ImageBuffer, window: &Window) -> (f64, f64) {
+    let values: Vec<f64> = window
+        .pixels()
+        .map(|(y, x)| buffer.get(x, y) as f64)
+        .collect();
+    mean_and_variance(&values)
+}
+
+fn rms_contrast((mean, variance): (f64, f64)) -> f64 {
+    if mean > 0.0 {
+        variance.sqrt() / mean
+    } else {
+        0.0
+    }
+}
+
+fn local_contrast(buffer: &ImageBuffer, window_size: usize, obj_detection_threshold: f64) -> f64 {
+    let contrasts: Vec<f64> = iproduct!(0..CONTRAST_PATCH_GRID, 0..CONTRAST_PATCH_GRID)
+        .filter_map(|(row, column)| {
+            let x = buffer.width * (2 * column + 1) / (2 * CONTRAST_PATCH_GRID);
+            let y = buffer.height * (2 * row + 1) / (2 * CONTRAST_PATCH_GRID);
+            let window = Window::centered(buffer, window_size, x, y);
+            let (mean, variance) = window_mean_and_variance(buffer, &window);
+            (mean >= obj_detection_threshold).then(|| rms_contrast((mean, variance)))
+        })
+        .collect();
+    mean_and_variance(&contrasts).0
+}
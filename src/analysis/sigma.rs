@@ -1,155 +1,292 @@
 use anyhow::Result;
 use charts::{Chart, Color, LineSeriesView, MarkerType, ScaleLinear};
-use gtk::glib::Sender;
-use sciimg::{max, min, quality};
-use solhat::calibrationframe::CalibrationImage;
+use sciimg::{max, min};
 use solhat::context::ProcessContext;
 use solhat::framerecord::FrameRecord;
-use std::sync::{Arc, Mutex};
 use rayon::prelude::*;
 
+use crate::analysis::cache::AnalysisCache;
+use crate::analysis::quality;
 use crate::cancel::{self, *};
-use crate::state::build_solhat_parameters;
-use crate::taskstatus::*;
+use crate::state::{build_solhat_context, ParametersState};
+use crate::task::TaskContext;
 
 ///////////////////////////////////////////////////////
 // Sigma Anaysis
 ///////////////////////////////////////////////////////
 
-lazy_static! {
-    // NOTE: Concurrent processing threads will stomp on each other, but at least
-    // they'll do it in proper turn.  Also, this is stupid and can't stay this way.
-    static ref COUNTER: Arc<Mutex<usize>> = Arc::new(Mutex::new(0));
-}
-
 #[derive(Debug)]
 pub struct AnalysisRange {
     min: f64,
     max: f64,
 }
 
+/// Sigma of a single frame, along with which frame it is so it can be excluded
+#[derive(Debug, Clone)]
+pub struct AnalysisFrame {
+    pub source_file_id: String,
+    pub frame_id: usize,
+    pub sigma: f64,
+    /// Sigma within each of the analysis windows placed by the user, if any
+    pub region_sigmas: Vec<f64>,
+}
+
+/// A frame record with its center of mass offset and sigma filled in, and the sigma
+/// of each analysis window it was combined from
+#[derive(Debug, Clone)]
+pub struct FrameAnalysis {
+    pub frame_record: FrameRecord,
+    pub region_sigmas: Vec<f64>,
+}
+
 #[derive(Debug)]
 pub struct AnalysisSeries {
-    sigma_list: Vec<f64>,
+    /// In the order the frames were captured
+    pub frames: Vec<AnalysisFrame>,
+}
+
+/// Top, right, bottom and left margins around the plot area of the chart
+pub const CHART_MARGINS: (isize, isize, isize, isize) = (0, 40, 50, 60);
+
+/// Colors of the analysis windows, used for both their series in the chart and their
+/// outline on the light preview
+const REGION_COLORS: [(u8, u8, u8); 6] = [
+    (31, 119, 180),
+    (44, 160, 44),
+    (148, 103, 189),
+    (23, 190, 207),
+    (227, 119, 194),
+    (140, 86, 75),
+];
+
+pub fn region_color(index: usize) -> (u8, u8, u8) {
+    REGION_COLORS[index % REGION_COLORS.len()]
 }
 
 impl AnalysisSeries {
+    pub fn sigma_list(&self) -> Vec<f64> {
+        self.frames.iter().map(|f| f.sigma).collect()
+    }
+
     pub fn sorted_list(&self) -> Vec<f64> {
-        let mut sorted = self.sigma_list.clone();
-        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
-        sorted.reverse();
+        let mut sorted = self.sigma_list();
+        sorted.sort_by(|a, b| b.total_cmp(a));
         sorted
     }
 
+    /// Number of analysis windows each frame has a sigma for
+    pub fn region_count(&self) -> usize {
+        self.frames
+            .first()
+            .map(|f| f.region_sigmas.len())
+            .unwrap_or(0)
+    }
+
+    /// Sigma of each frame within the analysis window at `region`
+    pub fn region_sigma_list(&self, region: usize) -> Vec<f64> {
+        self.frames.iter().map(|f| f.region_sigmas[region]).collect()
+    }
+
     pub fn minmax(&self) -> AnalysisRange {
         let mut mn = std::f64::MAX;
         let mut mx = std::f64::MIN;
 
-        self.sigma_list.iter().for_each(|s| {
-            mn = min!(*s, mn);
-            mx = max!(*s, mx);
-        });
+        self.frames
+            .iter()
+            .flat_map(|f| std::iter::once(&f.sigma).chain(f.region_sigmas.iter()))
+            .for_each(|s| {
+                mn = min!(*s, mn);
+                mx = max!(*s, mx);
+            });
 
         AnalysisRange { min: mn, max: mx }
     }
 
+    /// Frames within the min/max sigma limits, best first. Frames excluded by hand are
+    /// left out before any limit is applied, as in processing.
+    fn sorted_within_limits(&self, params: &ParametersState) -> Vec<&AnalysisFrame> {
+        let mut sorted: Vec<&AnalysisFrame> = self
+            .frames
+            .iter()
+            .filter(|f| !params.is_frame_excluded(&f.source_file_id, f.frame_id))
+            .filter(|f| f.sigma >= params.min_sigma && f.sigma <= params.max_sigma)
+            .collect();
+        sorted.sort_by(|a, b| b.sigma.total_cmp(&a.sigma));
+        sorted
+    }
+
+    /// How many of the frames within the sigma limits are kept by the top percentage
+    /// and maximum frame count, the way SolHat's frame limiting applies them
+    fn kept_count(params: &ParametersState, within_limits: usize) -> usize {
+        ((within_limits as f64 * params.top_percentage / 100.0).round() as usize)
+            .min(params.max_frames)
+    }
+
+    /// Sigma of the worst frame kept by the top percentage
+    pub fn top_percentage_cutoff(&self, params: &ParametersState) -> Option<f64> {
+        let sorted = self.sorted_within_limits(params);
+        let kept = Self::kept_count(params, sorted.len());
+        match kept {
+            0 => None,
+            _ => Some(sorted[kept - 1].sigma),
+        }
+    }
+
+    /// The top percentage that keeps the frames within the sigma limits with a sigma of
+    /// at least `cutoff`
+    pub fn top_percentage_for_cutoff(&self, params: &ParametersState, cutoff: f64) -> f64 {
+        let sorted = self.sorted_within_limits(params);
+        if sorted.is_empty() {
+            return params.top_percentage;
+        }
+        let above = sorted.iter().filter(|f| f.sigma >= cutoff).count();
+        (above as f64 / sorted.len() as f64 * 100.0)
+            .round()
+            .clamp(1.0, 100.0)
+    }
+
+    /// Number of frames which will be stacked with these parameters
+    pub fn surviving_frames(&self, params: &ParametersState) -> usize {
+        Self::kept_count(params, self.sorted_within_limits(params).len())
+    }
+
     pub fn sma(&self, window: usize) -> Vec<f64> {
+        let sigma_list = self.sigma_list();
         let half_win = window / 2;
         let mut sma: Vec<f64> = vec![];
-        (0..self.sigma_list.len()).for_each(|i| {
+        (0..sigma_list.len()).for_each(|i| {
             let start = if i <= half_win { 0 } else { i - half_win };
 
-            let end = if i + half_win <= self.sigma_list.len() {
+            let end = if i + half_win <= sigma_list.len() {
                 i + half_win
             } else {
-                self.sigma_list.len()
+                sigma_list.len()
             };
-            let s = self.sigma_list[start..end].iter().sum::<f64>() / (end - start) as f64;
+            let s = sigma_list[start..end].iter().sum::<f64>() / (end - start) as f64;
             sma.push(s);
         });
         sma
     }
 }
 
-pub fn run_sigma_analysis(
-    master_sender: Sender<TaskStatusContainer>,
-) -> Result<AnalysisSeries, TaskCompletion> {
-    let params = match build_solhat_parameters() {
-        Ok(params) => params,
-        Err(why) => return Err(cancel::TaskCompletion::Error(format!("Error: {:?}", why))),
-    };
+/// Keeps the frames within the min/max sigma limits, then the best of those by the top
+/// percentage and maximum frame count, the way SolHat's frame limiting applies them.
+/// The frames kept are returned best first.
+pub fn apply_frame_limits<T, F>(frames: Vec<T>, params: &ParametersState, sigma: F) -> Vec<T>
+where
+    F: Fn(&T) -> f64,
+{
+    let mut within_limits: Vec<T> = frames
+        .into_iter()
+        .filter(|f| sigma(f) >= params.min_sigma && sigma(f) <= params.max_sigma)
+        .collect();
+    within_limits.sort_by(|a, b| sigma(b).total_cmp(&sigma(a)));
+    let kept = ((within_limits.len() as f64 * params.top_percentage / 100.0).round() as usize)
+        .min(params.max_frames);
+    within_limits.truncate(kept);
+    within_limits
+}
 
-    let context = match ProcessContext::create_with_calibration_frames(
-        &params,
-        CalibrationImage::new_empty(),
-        CalibrationImage::new_empty(),
-        CalibrationImage::new_empty(),
-        CalibrationImage::new_empty(),
-    ) {
+/// Analyzes every frame of the light inputs. The frames are calibrated the same way as
+/// in processing, so the sigmas match those the frame limits are applied to and the
+/// cached results can be shared with a full run. Masters are reused from the library
+/// when it has them for the same calibration inputs.
+pub fn run_sigma_analysis(task: &TaskContext) -> Result<AnalysisSeries, TaskCompletion> {
+    // Analysis is run over and over while tuning, so it leaves saving masters to
+    // processing
+    let context = match build_solhat_context(task, false) {
         Ok(context) => context,
-        Err(why) => return Err(cancel::TaskCompletion::Error(format!("Error: {:?}", why))),
+        Err(why) => {
+            return Err(match why.downcast_ref::<TaskCompletion>() {
+                Some(TaskCompletion::Cancelled) => TaskCompletion::Cancelled,
+                _ => TaskCompletion::Error(why.to_string()),
+            })
+        }
     };
 
-    check_cancel_status(&master_sender)?;
+    task.check_cancel_status()?;
     let frame_count = context.frame_records.len();
-    *COUNTER.lock().unwrap() = 0;
-    let sender = master_sender.clone();
-    set_task_status(&sender, "Frame Analysis", frame_count, 0);
-    let frame_records = match frame_analysis_window_size(
+    task.reset_progress();
+    let progress_task = task.clone();
+    task.set_status("Frame Analysis", frame_count, 0);
+    let result = frame_analysis_window_size(
         &context,
-        context.parameters.analysis_window_size,
+        &task.params,
+        &task.cancel_token(),
         move |fr| {
             info!(
                 "frame_sigma_analysis(): Frame processed with sigma {}",
                 fr.sigma
             );
 
-            let mut c = COUNTER.lock().unwrap();
-            *c += 1;
-            set_task_status(&sender, "Frame Analysis", frame_count, *c);
-            // check_cancel_status(&sender)
+            let c = progress_task.advance_progress();
+            progress_task.set_status("Frame Analysis", frame_count, c);
         },
-    ) {
-        Ok(frame_records) => frame_records,
-        Err(why) => return Err(cancel::TaskCompletion::Error(format!("Error: {:?}", why))),
+    );
+
+    // Reports the cancel if it stopped the analysis part way through
+    task.check_cancel_status()?;
+    let analyses = match result {
+        Ok(analyses) => analyses,
+        Err(why) => return Err(cancel::TaskCompletion::Error(why.to_string())),
     };
 
-    let mut sigma_list: Vec<f64> = vec![];
-    frame_records
-        .iter()
-        .filter(|fr| {
-            let min_sigma = context.parameters.min_sigma.unwrap_or(std::f64::MIN);
-            let max_sigma = context.parameters.max_sigma.unwrap_or(std::f64::MAX);
-            fr.sigma >= min_sigma && fr.sigma <= max_sigma
-        })
-        .for_each(|fr| {
-            sigma_list.push(fr.sigma);
+    // Every frame is kept, as the sigma limits are shown and set on the chart
+    let mut frames: Vec<AnalysisFrame> = vec![];
+    analyses
+        .into_iter()
+        .for_each(|a| {
+            frames.push(AnalysisFrame {
+                source_file_id: a.frame_record.source_file_id.to_owned(),
+                frame_id: a.frame_record.frame_id,
+                sigma: a.frame_record.sigma,
+                region_sigmas: a.region_sigmas,
+            });
         });
 
-    set_task_completed(&master_sender);
+    task.set_completed();
 
     Ok(AnalysisSeries {
-        sigma_list,
+        frames,
     })
 }
 
 
 /// Combined method of center-of-mass and sigma analysis. This is to limit the number of
-/// frame reads from disk which are rather expensive in terms of CPU and time.
+/// frame reads from disk which are rather expensive in terms of CPU and time. Results are
+/// cached per SER file, so unchanged files aren't read again. With analysis windows
+/// placed by the user, each frame's sigma combines theirs rather than using the disk
+/// center.
 pub fn frame_analysis_window_size<F>(
     context: &ProcessContext,
-    window_size: usize,
+    params: &ParametersState,
+    cancel_token: &CancelToken,
     on_frame_checked: F,
-) -> Result<Vec<FrameRecord>>
+) -> Result<Vec<FrameAnalysis>>
 where
     F: Fn(&FrameRecord) + Send + Sync + 'static,
 {
-    let frame_records: Vec<FrameRecord> = context
+    let window_size = params.analysis_window_size;
+    let metric = params.quality_metric;
+    let cache = AnalysisCache::load(context, params);
+    let analyses: Vec<FrameAnalysis> = context
         .frame_records
         .par_iter()
-        .map(|fr| {
-            let mut fr_copy = fr.clone();
-            let frame = fr.get_frame(context).expect("");
+        .map(|fr| -> Result<FrameAnalysis> {
+            if cancel_token.is_cancel_requested() {
+                return Err(TaskCompletion::Cancelled.into());
+            }
+            let mut analysis = FrameAnalysis {
+                frame_record: fr.clone(),
+                region_sigmas: vec![],
+            };
+            if let Some(cached) = cache.get(&fr.source_file_id, fr.frame_id) {
+                cached.apply_to(&mut analysis);
+                on_frame_checked(&analysis.frame_record);
+                return Ok(analysis);
+            }
+            let frame = fr.get_frame(context)?;
+            let fr_copy = &mut analysis.frame_record;
 
             fr_copy.offset = frame
                 .buffer
@@ -160,26 +297,42 @@ where
 
             // If monochrome, this will perform the analysis on the only band. If RGB, we perform analysis
             // on the red band.
-            fr_copy.sigma = quality::get_point_quality_estimation_on_buffer(
-                frame.buffer.get_band(0),
+            let band = frame.buffer.get_band(0);
+            analysis.region_sigmas = quality::region_qualities(
+                metric,
+                band,
                 window_size,
-                x,
-                y,
-            ) as f64;
+                frame.buffer.width as f64 / 2.0 + fr_copy.offset.h as f64,
+                frame.buffer.height as f64 / 2.0 + fr_copy.offset.v as f64,
+                &params.analysis_regions,
+            );
+            fr_copy.sigma = if analysis.region_sigmas.is_empty() {
+                quality::frame_quality(
+                    metric,
+                    band,
+                    window_size,
+                    x,
+                    y,
+                    context.parameters.obj_detection_threshold,
+                )
+            } else {
+                params.region_combine.combine(&analysis.region_sigmas)
+            };
 
-            on_frame_checked(&fr_copy);
-            fr_copy
+            on_frame_checked(&analysis.frame_record);
+            Ok(analysis)
         })
-        .collect();
-    Ok(frame_records)
+        .collect::<Result<Vec<FrameAnalysis>>>()?;
+    cache.save(&analyses);
+    Ok(analyses)
 }
 
 // Based on https://github.com/askanium/rustplotlib/blob/master/examples/line_series_chart.rs
 pub fn create_chart(data: &AnalysisSeries, width: isize, height: isize) -> Result<String> {
-    let (top, right, bottom, left) = (0, 40, 50, 60);
+    let (top, right, bottom, left) = CHART_MARGINS;
 
     let x = ScaleLinear::new()
-        .set_domain(vec![0_f32, data.sigma_list.len() as f32])
+        .set_domain(vec![0_f32, data.frames.len() as f32])
         .set_range(vec![0, width - left - right]);
 
     let rng = data.minmax();
@@ -196,17 +349,17 @@ pub fn create_chart(data: &AnalysisSeries, width: isize, height: isize) -> Resul
         .collect();
 
     let line_data_2: Vec<(f32, f32)> = data
-        .sma(data.sigma_list.len() / 20)
+        .sma(data.frames.len() / 20)
         .iter()
         .enumerate()
         .map(|(i, s)| (i as f32, *s as f32))
         .collect();
 
     let line_data_3: Vec<(f32, f32)> = data
-        .sigma_list
+        .frames
         .iter()
         .enumerate()
-        .map(|(i, s)| (i as f32, *s as f32))
+        .map(|(i, f)| (i as f32, f.sigma as f32))
         .collect();
 
     let line_view_1 = LineSeriesView::new()
@@ -239,11 +392,43 @@ pub fn create_chart(data: &AnalysisSeries, width: isize, height: isize) -> Resul
         .load_data(&line_data_3)
         .unwrap();
 
+    // A series per analysis window, drawn underneath the combined sigma
+    let region_data: Vec<Vec<(f32, f32)>> = (0..data.region_count())
+        .map(|region| {
+            data.region_sigma_list(region)
+                .iter()
+                .enumerate()
+                .map(|(i, s)| (i as f32, *s as f32))
+                .collect()
+        })
+        .collect();
+
+    let region_views: Vec<LineSeriesView<f32, f32>> = region_data
+        .iter()
+        .enumerate()
+        .map(|(region, line_data)| {
+            let (r, g, b) = region_color(region);
+            let color = format!("#{:02X}{:02X}{:02X}", r, g, b);
+            LineSeriesView::new()
+                .set_x_scale(&x)
+                .set_y_scale(&y)
+                .set_marker_type(MarkerType::X)
+                .set_label_visibility(false)
+                .set_marker_visibility(false)
+                .set_colors(Color::from_vec_of_hex_strings(vec![color.as_str()]))
+                .load_data(line_data)
+                .unwrap()
+        })
+        .collect();
+
     // Generate and save the chart.
-    let svg = Chart::new()
+    let chart = Chart::new()
         .set_width(width)
         .set_height(height)
-        .set_margins(top, right, bottom, left)
+        .set_margins(top, right, bottom, left);
+    let svg = region_views
+        .iter()
+        .fold(chart, |chart, view| chart.add_view(view))
         .add_view(&line_view_3)
         .add_view(&line_view_2)
         .add_view(&line_view_1)
@@ -255,3 +440,36 @@ pub fn create_chart(data: &AnalysisSeries, width: isize, height: isize) -> Resul
         .unwrap();
     Ok(svg)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn series(sigmas: &[f64]) -> AnalysisSeries {
+        AnalysisSeries {
+            frames: sigmas
+                .iter()
+                .enumerate()
+                .map(|(frame_id, sigma)| AnalysisFrame {
+                    source_file_id: "test.ser".to_owned(),
+                    frame_id,
+                    sigma: *sigma,
+                    region_sigmas: vec![],
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn sorted_list_is_best_first() {
+        assert_eq!(series(&[2.0, 5.0, 1.0]).sorted_list(), vec![5.0, 2.0, 1.0]);
+    }
+
+    #[test]
+    fn sorted_list_with_nan_sigma() {
+        let sorted = series(&[2.0, f64::NAN, 5.0, 1.0]).sorted_list();
+        assert_eq!(sorted.len(), 4);
+        let numbers: Vec<f64> = sorted.into_iter().filter(|s| !s.is_nan()).collect();
+        assert_eq!(numbers, vec![5.0, 2.0, 1.0]);
+    }
+}
@@ -1,22 +1,21 @@
 use anyhow::Result;
 
-use gtk::glib::Sender;
 use sciimg::prelude::*;
 use solhat::calibrationframe::CalibrationImage;
 use solhat::context::ProcessContext;
 use solhat::threshtest::compute_rgb_threshtest_image;
 
 use crate::state::build_solhat_parameters;
-use crate::taskstatus::*;
+use crate::task::TaskContext;
 
 ///////////////////////////////////////////////////////
 /// Threshold Testing
 ///////////////////////////////////////////////////////
 
-pub fn run_thresh_test(master_sender: Sender<TaskStatusContainer>) -> Result<Image> {
-    set_task_status(&master_sender, "Processing Threshold Test", 2, 1);
+pub fn run_thresh_test(task: &TaskContext) -> Result<Image> {
+    task.set_status("Processing Threshold Test", 2, 1);
     let context = ProcessContext::create_with_calibration_frames(
-        &build_solhat_parameters()?,
+        &build_solhat_parameters(&task.params)?,
         CalibrationImage::new_empty(),
         CalibrationImage::new_empty(),
         CalibrationImage::new_empty(),
@@ -29,6 +28,6 @@ pub fn run_thresh_test(master_sender: Sender<TaskStatusContainer>) -> Result<Ima
         context.parameters.obj_detection_threshold as f32,
     );
 
-    set_task_completed(&master_sender);
+    task.set_completed();
     Ok(result)
 }
@@ -0,0 +1,138 @@
+use anyhow::Result;
+use rayon::prelude::*;
+use sciimg::prelude::*;
+use solhat::ser::SerFile;
+use std::cmp::Ordering;
+
+use crate::calibration::CombineMethod;
+
+///////////////////////////////////////////////////////
+/// Per-pixel Combining of Calibration Frames
+///////////////////////////////////////////////////////
+
+/// Rejection threshold, in standard deviations, for the kappa-sigma clipped mean
+const KAPPA: f32 = 3.0;
+
+/// Maximum number of rejection passes for the kappa-sigma clipped mean
+const KAPPA_SIGMA_ITERATIONS: usize = 3;
+
+/// Limits how many values are held in memory at once. Frames are combined in batches
+/// so large SER files don't need to be loaded in full, and each frame is only read once.
+const MAX_BATCH_VALUES: usize = 64 * 1024 * 1024;
+
+fn mean(values: &[f32]) -> f32 {
+    if values.is_empty() {
+        0.0
+    } else {
+        values.iter().sum::<f32>() / values.len() as f32
+    }
+}
+
+fn median(values: &mut [f32]) -> f32 {
+    values.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+    let n = values.len();
+    if n == 0 {
+        0.0
+    } else if n % 2 == 1 {
+        values[n / 2]
+    } else {
+        (values[n / 2 - 1] + values[n / 2]) / 2.0
+    }
+}
+
+/// Mean of the values after repeatedly rejecting those further than `KAPPA` standard
+/// deviations from the mean. Removes outliers like cosmic ray hits from darks.
+fn kappa_sigma_mean(values: &[f32]) -> f32 {
+    let mut kept = values.to_vec();
+    for _ in 0..KAPPA_SIGMA_ITERATIONS {
+        if kept.len() < 3 {
+            break;
+        }
+        let m = mean(&kept);
+        let stddev = (kept.iter().map(|v| (v - m).powi(2)).sum::<f32>() / kept.len() as f32).sqrt();
+        let before = kept.len();
+        kept.retain(|v| (v - m).abs() <= KAPPA * stddev);
+        if kept.is_empty() {
+            return m;
+        } else if kept.len() == before {
+            break;
+        }
+    }
+    mean(&kept)
+}
+
+impl CombineMethod {
+    fn combine(&self, values: &mut [f32]) -> f32 {
+        match self {
+            CombineMethod::Mean => mean(values),
+            CombineMethod::Median => median(values),
+            CombineMethod::KappaSigma => kappa_sigma_mean(values),
+        }
+    }
+}
+
+/// Combines all frames in a SER file into a single image, pixel by pixel. When the
+/// frames don't all fit in memory, they're combined in batches of consecutive frames
+/// and the batch results averaged, weighted by batch size.
+pub fn combine_ser_file(ser_file_path: &str, method: CombineMethod) -> Result<Image> {
+    let ser_file = SerFile::load_ser(ser_file_path)?;
+    let num_frames = ser_file.frame_count;
+    if num_frames == 0 {
+        return Err(anyhow!("No frames in {}", ser_file_path));
+    }
+
+    let first_frame = ser_file.get_frame(0)?;
+    let width = first_frame.buffer.width;
+    let height = first_frame.buffer.height;
+    let num_bands = first_frame.buffer.num_bands();
+    let pixels = num_bands * height * width;
+
+    // Batches are kept about the same size so none of them is combined from only a
+    // few frames
+    let max_batch_frames = (MAX_BATCH_VALUES / pixels).clamp(1, num_frames);
+    let num_batches = num_frames.div_ceil(max_batch_frames);
+    let batch_frames = num_frames.div_ceil(num_batches);
+    info!(
+        "Combining {} frames from {} with {:?}, in {} batches",
+        num_frames, ser_file_path, method, num_batches
+    );
+
+    // Sum of each pixel's batch results, weighted by the number of frames in the batch
+    let mut sums = vec![0.0_f64; pixels];
+    for first in (0..num_frames).step_by(batch_frames) {
+        let count = batch_frames.min(num_frames - first);
+
+        // Each pixel's values across the batch are kept together so they can be
+        // combined independently
+        let mut values = vec![0.0_f32; pixels * count];
+        for i in 0..count {
+            let frame = ser_file.get_frame(first + i)?;
+            for b in 0..num_bands {
+                let band = frame.buffer.get_band(b);
+                for y in 0..height {
+                    for x in 0..width {
+                        let p = (b * height + y) * width + x;
+                        values[p * count + i] = band.get(x, y);
+                    }
+                }
+            }
+        }
+
+        values
+            .par_chunks_mut(count)
+            .zip(sums.par_iter_mut())
+            .for_each(|(v, sum)| *sum += method.combine(v) as f64 * count as f64);
+    }
+
+    let mut combined = Image::new_with_bands(width, height, num_bands, ImageMode::U16BIT)?;
+    for b in 0..num_bands {
+        for y in 0..height {
+            for x in 0..width {
+                let p = (b * height + y) * width + x;
+                combined.put(x, y, (sums[p] / num_frames as f64) as f32, b);
+            }
+        }
+    }
+
+    Ok(combined)
+}
@@ -0,0 +1,481 @@
+use anyhow::Result;
+use sciimg::prelude::*;
+use serde::{Deserialize, Serialize};
+use solhat::calibrationframe::{CalibrationImage, ComputeMethod};
+use solhat::ser::SerFile;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::conversion::open_image_file;
+use crate::fits;
+
+mod combine;
+use crate::state::{solhat_config_dir, ParametersState};
+
+///////////////////////////////////////////////////////
+/// Master Calibration Frames & Library
+///////////////////////////////////////////////////////
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CalibrationKind {
+    Flat,
+    DarkFlat,
+    Dark,
+    Bias,
+}
+
+impl fmt::Display for CalibrationKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            CalibrationKind::Flat => "Flat",
+            CalibrationKind::DarkFlat => "Dark Flat",
+            CalibrationKind::Dark => "Dark",
+            CalibrationKind::Bias => "Bias",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// File format used when saving computed masters
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum MasterFormat {
+    #[default]
+    Fits,
+    Tiff,
+}
+
+impl MasterFormat {
+    pub fn extension(&self) -> &str {
+        match self {
+            MasterFormat::Fits => "fits",
+            MasterFormat::Tiff => "tif",
+        }
+    }
+}
+
+/// How the frames of a calibration SER are combined into a master
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum CombineMethod {
+    #[default]
+    Mean,
+    Median,
+    /// Mean after rejecting outliers beyond a number of standard deviations
+    KappaSigma,
+}
+
+impl CombineMethod {
+    /// Identifier used by the combine method selectors in the UI
+    pub fn id(&self) -> &'static str {
+        match self {
+            CombineMethod::Mean => "Mean",
+            CombineMethod::Median => "Median",
+            CombineMethod::KappaSigma => "KappaSigma",
+        }
+    }
+
+    pub fn from_id(id: &str) -> Self {
+        match id {
+            "Median" => CombineMethod::Median,
+            "KappaSigma" => CombineMethod::KappaSigma,
+            _ => CombineMethod::Mean,
+        }
+    }
+}
+
+/// Identifies the camera configuration a calibration frame was captured with. The
+/// camera and frame size come from the SER header. SER doesn't store gain, exposure
+/// or temperature, so those are read from the capture settings text file written
+/// by FireCapture or SharpCap next to the SER, if there is one.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+pub struct CameraKey {
+    pub camera: String,
+    pub width: usize,
+    pub height: usize,
+    pub gain: Option<f64>,
+    pub exposure_ms: Option<f64>,
+    pub temperature: Option<f64>,
+}
+
+/// Relative tolerance when comparing exposures
+const EXPOSURE_TOLERANCE: f64 = 0.02;
+
+/// Tolerance in degrees C when comparing sensor temperatures
+const TEMPERATURE_TOLERANCE: f64 = 3.0;
+
+fn first_number(s: &str) -> Option<f64> {
+    let start = s.find(|c: char| c.is_ascii_digit() || c == '-' || c == '.')?;
+    let num: String = s[start..]
+        .chars()
+        .take_while(|c| c.is_ascii_digit() || *c == '-' || *c == '.')
+        .collect();
+    num.parse().ok()
+}
+
+/// Parses an exposure value into milliseconds, e.g. "0.500ms", "250us", "0.01s".
+/// Values without units are assumed to already be milliseconds.
+fn parse_exposure_ms(s: &str) -> Option<f64> {
+    let v = first_number(s)?;
+    let lower = s.to_lowercase();
+    if lower.contains("us") || lower.contains('µ') {
+        Some(v / 1000.0)
+    } else if lower.contains("ms") {
+        Some(v)
+    } else if lower.trim_end().ends_with('s') {
+        Some(v * 1000.0)
+    } else {
+        Some(v)
+    }
+}
+
+fn matches_within(a: Option<f64>, b: Option<f64>, tolerance: f64, relative: bool) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => {
+            let limit = if relative {
+                a.abs().max(b.abs()) * tolerance
+            } else {
+                tolerance
+            };
+            (a - b).abs() <= limit
+        }
+        _ => true, // Can't tell, so don't exclude it
+    }
+}
+
+impl CameraKey {
+    pub fn from_ser_file(ser_file_path: &Path) -> Result<Self> {
+        let ser_file = SerFile::load_ser(ser_file_path.to_str().unwrap())?;
+        let mut key = CameraKey {
+            camera: ser_file.instrument.trim().to_owned(),
+            width: ser_file.image_width,
+            height: ser_file.image_height,
+            ..Default::default()
+        };
+
+        if let Some(settings_file) = CameraKey::find_capture_settings(ser_file_path) {
+            info!("Reading capture settings from {:?}", settings_file);
+            let settings = fs::read_to_string(settings_file)?;
+            settings.lines().for_each(|line| {
+                if let Some((k, v)) = line.split_once(['=', ':']) {
+                    let k = k.trim().to_lowercase();
+                    if k == "gain" {
+                        key.gain = first_number(v);
+                    } else if k == "shutter" || k == "exposure" {
+                        key.exposure_ms = parse_exposure_ms(v);
+                    } else if k.contains("temperature") {
+                        key.temperature = first_number(v);
+                    }
+                }
+            });
+        }
+        Ok(key)
+    }
+
+    /// FireCapture writes `<name>.txt`, SharpCap writes `<name>.ser.CameraSettings.txt`
+    fn find_capture_settings(ser_file_path: &Path) -> Option<PathBuf> {
+        let mut sharpcap = ser_file_path.as_os_str().to_owned();
+        sharpcap.push(".CameraSettings.txt");
+        [ser_file_path.with_extension("txt"), PathBuf::from(sharpcap)]
+            .into_iter()
+            .find(|p| p.exists())
+    }
+
+    /// Whether a master with this key is suitable for calibrating data with `other`.
+    /// Flats are taken at their own exposure, so only darks and bias frames need the
+    /// exposure to match.
+    pub fn is_compatible(&self, other: &CameraKey, kind: CalibrationKind) -> bool {
+        let base = self.camera == other.camera
+            && self.width == other.width
+            && self.height == other.height
+            && matches_within(self.gain, other.gain, 0.0, false);
+        match kind {
+            CalibrationKind::Dark => {
+                base && matches_within(
+                    self.exposure_ms,
+                    other.exposure_ms,
+                    EXPOSURE_TOLERANCE,
+                    true,
+                ) && matches_within(
+                    self.temperature,
+                    other.temperature,
+                    TEMPERATURE_TOLERANCE,
+                    false,
+                )
+            }
+            CalibrationKind::Bias => {
+                base && matches_within(
+                    self.temperature,
+                    other.temperature,
+                    TEMPERATURE_TOLERANCE,
+                    false,
+                )
+            }
+            CalibrationKind::Flat | CalibrationKind::DarkFlat => base,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct MasterEntry {
+    pub kind: CalibrationKind,
+    pub key: CameraKey,
+    pub path: PathBuf,
+    pub source: PathBuf,
+    pub created: String,
+    #[serde(default)]
+    pub method: CombineMethod,
+    /// Size and modification time of the source when the master was computed
+    #[serde(default)]
+    pub source_stamp: (u64, u64),
+}
+
+impl MasterEntry {
+    /// Whether this master was computed from `source` as it is now, using `method`
+    fn is_computed_from(
+        &self,
+        kind: CalibrationKind,
+        source: &Path,
+        method: CombineMethod,
+        source_stamp: (u64, u64),
+    ) -> bool {
+        self.kind == kind
+            && self.source == source
+            && self.method == method
+            && self.source_stamp == source_stamp
+    }
+}
+
+/// Size and modification time of a file, in nanoseconds since the epoch, to tell
+/// whether it has changed since something was computed from it
+pub fn file_stamp(path: &Path) -> Result<(u64, u64)> {
+    let metadata = fs::metadata(path)?;
+    Ok((
+        metadata.len(),
+        metadata.modified()?.duration_since(UNIX_EPOCH)?.as_nanos() as u64,
+    ))
+}
+
+/// Index of saved masters, kept in `~/.solhat/calibration/library.toml`
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct CalibrationLibrary {
+    pub masters: Vec<MasterEntry>,
+}
+
+fn library_dir() -> Result<PathBuf> {
+    let dir = solhat_config_dir()?.join("calibration");
+    if !dir.exists() {
+        fs::create_dir(&dir)?;
+    }
+    Ok(dir)
+}
+
+impl CalibrationLibrary {
+    pub fn load_from_userhome() -> Result<Self> {
+        let library_file_path = library_dir()?.join("library.toml");
+        if library_file_path.exists() {
+            let t = std::fs::read_to_string(library_file_path)?;
+            let mut library: CalibrationLibrary = toml::from_str(&t)?;
+            library.masters.retain(|m| m.path.exists());
+            Ok(library)
+        } else {
+            Ok(CalibrationLibrary::default())
+        }
+    }
+
+    pub fn save_to_userhome(&self) -> Result<()> {
+        let toml_str = toml::to_string(&self)?;
+        let library_file_path = library_dir()?.join("library.toml");
+        let mut f = File::create(library_file_path)?;
+        f.write_all(toml_str.as_bytes())?;
+        Ok(())
+    }
+
+    /// Writes a computed master to the library directory and records it. A master
+    /// computed again from the same unchanged source and method replaces the earlier
+    /// one, written over its file when the format is the same.
+    pub fn add_master(
+        &mut self,
+        kind: CalibrationKind,
+        source: &Path,
+        image: &Image,
+        method: CombineMethod,
+        format: MasterFormat,
+    ) -> Result<PathBuf> {
+        let key = CameraKey::from_ser_file(source)?;
+        let source_stamp = file_stamp(source)?;
+        let now = chrono::Local::now();
+        let camera: String = key
+            .camera
+            .chars()
+            .map(|c| if c.is_alphanumeric() { c } else { '_' })
+            .collect();
+        let filename = format!(
+            "{}_{}_{}.{}",
+            kind.to_string().replace(' ', ""),
+            camera,
+            now.format("%Y%m%d_%H%M%S"),
+            format.extension()
+        );
+        let replaced = self
+            .masters
+            .iter()
+            .position(|m| m.is_computed_from(kind, source, method, source_stamp))
+            .map(|i| self.masters.remove(i));
+        let path = match &replaced {
+            Some(old) if old.path.extension() == Some(OsStr::new(format.extension())) => {
+                old.path.to_owned()
+            }
+            _ => library_dir()?.join(filename),
+        };
+        save_master_image(image, &path)?;
+        info!("Saved master {} to {:?}", kind, path);
+
+        if let Some(old) = replaced.filter(|old| old.path != path) {
+            if let Err(why) = fs::remove_file(&old.path) {
+                warn!("Unable to remove replaced master {:?}: {:?}", old.path, why);
+            }
+        }
+
+        self.masters.push(MasterEntry {
+            kind,
+            key,
+            path: path.to_owned(),
+            source: source.to_owned(),
+            created: now.to_rfc3339(),
+            method,
+            source_stamp,
+        });
+        Ok(path)
+    }
+
+    /// Finds the most recent compatible master of a kind
+    pub fn find(&self, kind: CalibrationKind, key: &CameraKey) -> Option<&MasterEntry> {
+        self.masters
+            .iter()
+            .filter(|m| m.kind == kind && m.key.is_compatible(key, kind))
+            .max_by(|a, b| a.created.cmp(&b.created))
+    }
+
+    /// Finds a master computed from `source` as it is now, using `method`
+    pub fn find_computed_from(
+        &self,
+        kind: CalibrationKind,
+        source: &Path,
+        method: CombineMethod,
+    ) -> Option<&MasterEntry> {
+        let source_stamp = file_stamp(source).ok()?;
+        self.masters
+            .iter()
+            .find(|m| m.is_computed_from(kind, source, method, source_stamp))
+    }
+}
+
+/// Whether the path is a precomputed master rather than a SER to be averaged
+pub fn is_master_file(path: &Path) -> bool {
+    matches!(
+        path.extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .as_deref(),
+        Some("fits") | Some("fit") | Some("fts") | Some("tif") | Some("tiff")
+    )
+}
+
+fn save_master_image(image: &Image, path: &Path) -> Result<()> {
+    if fits::is_fits_file(path) {
+        fits::save_image(image, path, &[])
+    } else {
+        Ok(image.save(path.to_string_lossy().as_ref())?)
+    }
+}
+
+/// Loads a master calibration frame, or computes it from a SER file. A master already
+/// in the library for the same unchanged SER and method is used instead of computing
+/// it again. Computed masters are saved into the library if `save_masters` is set and
+/// it's requested in the parameters.
+pub fn load_or_compute_master(
+    kind: CalibrationKind,
+    inputs: &Option<String>,
+    method: CombineMethod,
+    params_state: &ParametersState,
+    save_masters: bool,
+) -> Result<CalibrationImage> {
+    let inputs = if let Some(inputs) = inputs {
+        inputs
+    } else {
+        return Ok(CalibrationImage::new_empty());
+    };
+
+    let input_path = Path::new(inputs);
+    if is_master_file(input_path) {
+        info!("Loading master {} from {:?}", kind, input_path);
+        return Ok(CalibrationImage {
+            image: Some(open_image_file(input_path)?),
+        });
+    }
+
+    if let Some(master) = load_library_master(kind, input_path, method) {
+        return Ok(master);
+    }
+
+    info!("Processing master {} using {:?}...", kind, method);
+    let master = match method {
+        CombineMethod::Mean => CalibrationImage::new_from_file(inputs, ComputeMethod::Mean)?,
+        CombineMethod::Median | CombineMethod::KappaSigma => CalibrationImage {
+            image: Some(combine::combine_ser_file(inputs, method)?),
+        },
+    };
+
+    if save_masters && params_state.save_masters {
+        if let Some(image) = &master.image {
+            // Not being able to save the master shouldn't stop the run
+            if let Err(why) = save_master_to_library(kind, input_path, image, method, params_state)
+            {
+                warn!("Failed to save master {}: {:?}", kind, why);
+            }
+        }
+    }
+    Ok(master)
+}
+
+/// A master from the library computed from `input_path` as it is now. Not being able to
+/// read the library only means the master is computed again.
+fn load_library_master(
+    kind: CalibrationKind,
+    input_path: &Path,
+    method: CombineMethod,
+) -> Option<CalibrationImage> {
+    let library = match CalibrationLibrary::load_from_userhome() {
+        Ok(library) => library,
+        Err(why) => {
+            warn!("Failed to load calibration library: {:?}", why);
+            return None;
+        }
+    };
+    let master = library.find_computed_from(kind, input_path, method)?;
+    match open_image_file(&master.path) {
+        Ok(image) => {
+            info!("Using library master {} from {:?}", kind, master.path);
+            Some(CalibrationImage { image: Some(image) })
+        }
+        Err(why) => {
+            warn!("Failed to load library master {:?}: {:?}", master.path, why);
+            None
+        }
+    }
+}
+
+fn save_master_to_library(
+    kind: CalibrationKind,
+    input_path: &Path,
+    image: &Image,
+    method: CombineMethod,
+    params_state: &ParametersState,
+) -> Result<()> {
+    let mut library = CalibrationLibrary::load_from_userhome()?;
+    library.add_master(kind, input_path, image, method, params_state.master_format)?;
+    library.save_to_userhome()
+}
@@ -1,16 +1,7 @@
-use crate::taskstatus::*;
-use anyhow::Result;
-use gtk::glib::Sender;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::{error::Error, fmt};
 
-#[derive(Debug, PartialEq, Eq)]
-pub enum CancelStatus {
-    NoStatus,        // Keep doing what you're doing...
-    CancelRequested, // Request cancel
-    Cancelled,       // Task has cancelled
-}
-
 #[derive(Debug, PartialEq, Eq)]
 pub enum TaskCompletion {
     Cancelled,
@@ -22,47 +13,27 @@ impl Error for TaskCompletion {}
 
 impl fmt::Display for TaskCompletion {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Error of type {:?}", self)
+        match self {
+            TaskCompletion::Cancelled => write!(f, "Cancelled"),
+            TaskCompletion::Completed => write!(f, "Completed"),
+            TaskCompletion::Error(why) => write!(f, "{}", why),
+        }
     }
 }
 
-pub struct CancelContainer {
-    pub status: CancelStatus,
-}
-
-lazy_static! {
-    pub static ref CANCEL_TASK: Arc<Mutex<CancelContainer>> =
-        Arc::new(Mutex::new(CancelContainer {
-            status: CancelStatus::NoStatus
-        }));
-}
-
-pub fn set_request_cancel() {
-    CANCEL_TASK.lock().unwrap().status = CancelStatus::CancelRequested;
+/// Cancel flag for a single task. Clones share the flag, so the user interface keeps
+/// one to cancel with while the task polls another.
+#[derive(Clone, Default, Debug)]
+pub struct CancelToken {
+    requested: Arc<AtomicBool>,
 }
 
-pub fn set_task_cancelled() {
-    CANCEL_TASK.lock().unwrap().status = CancelStatus::Cancelled;
-}
-
-pub fn reset_cancel_status() {
-    CANCEL_TASK.lock().unwrap().status = CancelStatus::NoStatus;
-}
-
-pub fn is_cancel_requested() -> bool {
-    CANCEL_TASK.lock().unwrap().status == CancelStatus::CancelRequested
-}
+impl CancelToken {
+    pub fn request_cancel(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+    }
 
-pub fn check_cancel_status(
-    sender: &Sender<TaskStatusContainer>,
-) -> Result<TaskCompletion, TaskCompletion> {
-    if is_cancel_requested() {
-        set_task_cancelled();
-        set_task_completed(sender);
-        reset_cancel_status();
-        warn!("Task cancellation request detected. Stopping progress");
-        Err(TaskCompletion::Cancelled)
-    } else {
-        Ok(TaskCompletion::Completed)
+    pub fn is_cancel_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
     }
 }
@@ -0,0 +1,169 @@
+use anyhow::Result;
+use itertools::iproduct;
+use rayon::prelude::*;
+use sciimg::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::sharpen::{convolve_separable, gaussian_kernel, mirror};
+use crate::task::TaskContext;
+
+///////////////////////////////////////////////////////
+/// Richardson-Lucy Deconvolution
+///////////////////////////////////////////////////////
+
+/// Ratio of a Gaussian's full width at half maximum to its sigma, 2 * sqrt(2 * ln 2)
+const FWHM_TO_SIGMA: f64 = 2.354_82;
+
+/// The Moffat PSF is cut off where it falls below this fraction of its peak
+const MOFFAT_CUTOFF: f64 = 0.001;
+
+/// Limits the PSF kernel to (2 * 25 + 1) pixels square
+const MAX_PSF_RADIUS: isize = 25;
+
+/// Keeps the estimate and its reblurred copy away from zero so the ratio stays finite
+const MIN_VALUE: f32 = 1.0e-6;
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum PsfShape {
+    #[default]
+    Gaussian,
+    Moffat,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct DeconvolutionParameters {
+    pub enabled: bool,
+    pub psf: PsfShape,
+    /// Full width at half maximum of the PSF, in pixels of the stacked image
+    pub fwhm: f64,
+    /// Moffat beta. Lower values give the PSF broader wings.
+    pub moffat_beta: f64,
+    pub iterations: usize,
+}
+
+impl Default for DeconvolutionParameters {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            psf: PsfShape::Gaussian,
+            fwhm: 2.5,
+            moffat_beta: 2.5,
+            iterations: 10,
+        }
+    }
+}
+
+/// A normalized point spread function kernel. The Gaussian is separable, so it's
+/// kept as one dimension and applied twice.
+enum PsfKernel {
+    Separable(Vec<f32>),
+    Full { kernel: Vec<f32>, radius: isize },
+}
+
+impl PsfKernel {
+    fn new(params: &DeconvolutionParameters) -> Self {
+        match params.psf {
+            PsfShape::Gaussian => {
+                PsfKernel::Separable(gaussian_kernel(params.fwhm / FWHM_TO_SIGMA))
+            }
+            PsfShape::Moffat => {
+                let beta = params.moffat_beta;
+                let alpha = params.fwhm / (2.0 * (2.0_f64.powf(1.0 / beta) - 1.0).sqrt());
+                let radius =
+                    (alpha * (MOFFAT_CUTOFF.powf(-1.0 / beta) - 1.0).sqrt()).ceil() as isize;
+                let radius = radius.clamp(1, MAX_PSF_RADIUS);
+
+                let kernel: Vec<f32> = iproduct!(-radius..=radius, -radius..=radius)
+                    .map(|(y, x)| {
+                        let r2 = (x * x + y * y) as f64;
+                        (1.0 + r2 / (alpha * alpha)).powf(-beta) as f32
+                    })
+                    .collect();
+                let sum: f32 = kernel.iter().sum();
+                PsfKernel::Full {
+                    kernel: kernel.iter().map(|k| k / sum).collect(),
+                    radius,
+                }
+            }
+        }
+    }
+
+    /// Convolves `data` with the PSF. Both PSF shapes are symmetric, so this is also
+    /// the correlation with the flipped PSF that Richardson-Lucy needs.
+    fn convolve(&self, data: &[f32], width: usize, height: usize) -> Vec<f32> {
+        match self {
+            PsfKernel::Separable(kernel) => convolve_separable(data, width, height, kernel, 1),
+            PsfKernel::Full { kernel, radius } => {
+                let size = (2 * radius + 1) as usize;
+                let mut result = vec![0.0; data.len()];
+                result
+                    .par_chunks_mut(width)
+                    .enumerate()
+                    .for_each(|(y, row)| {
+                        (0..width).for_each(|x| {
+                            row[x] = iproduct!(0..size, 0..size)
+                                .map(|(ky, kx)| {
+                                    let sx = mirror(x as isize + kx as isize - radius, width);
+                                    let sy = mirror(y as isize + ky as isize - radius, height);
+                                    kernel[ky * size + kx] * data[sy * width + sx]
+                                })
+                                .sum();
+                        });
+                    });
+                result
+            }
+        }
+    }
+}
+
+/// Deconvolves each band of the image with Richardson-Lucy, starting from the image
+/// itself. Works on the un-normalized stack, so the values keep their full precision.
+pub fn richardson_lucy(
+    image: &Image,
+    params: &DeconvolutionParameters,
+    task: &TaskContext,
+) -> Result<Image> {
+    let (width, height) = (image.width, image.height);
+    let psf = PsfKernel::new(params);
+
+    let observed: Vec<Vec<f32>> = (0..image.num_bands())
+        .map(|b| {
+            let band = image.get_band(b);
+            iproduct!(0..height, 0..width)
+                .map(|(y, x)| band.get(x, y).max(MIN_VALUE))
+                .collect()
+        })
+        .collect();
+    let mut estimates = observed.clone();
+
+    for i in 0..params.iterations {
+        task.check_cancel_status()?;
+        task.set_status("Deconvolving", params.iterations, i);
+
+        estimates
+            .iter_mut()
+            .zip(observed.iter())
+            .for_each(|(estimate, observed)| {
+                let reblurred = psf.convolve(estimate, width, height);
+                let ratio: Vec<f32> = observed
+                    .par_iter()
+                    .zip(reblurred.par_iter())
+                    .map(|(o, r)| o / r.max(MIN_VALUE))
+                    .collect();
+                let correction = psf.convolve(&ratio, width, height);
+                estimate
+                    .par_iter_mut()
+                    .zip(correction.par_iter())
+                    .for_each(|(e, c)| *e = (*e * c).max(MIN_VALUE));
+            });
+    }
+
+    let mut deconvolved = image.clone();
+    estimates.iter().enumerate().for_each(|(b, data)| {
+        iproduct!(0..height, 0..width).for_each(|(y, x)| {
+            deconvolved.put(x, y, data[y * width + x], b);
+        });
+    });
+    Ok(deconvolved)
+}
@@ -0,0 +1,201 @@
+use anyhow::Result;
+use itertools::iproduct;
+use sciimg::prelude::*;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+
+///////////////////////////////////////////////////////
+/// Minimal FITS Reading & Writing
+///////////////////////////////////////////////////////
+
+const BLOCK_SIZE: usize = 2880;
+const CARD_SIZE: usize = 80;
+
+pub enum HeaderValue {
+    Str(String),
+    Float(f64),
+    Int(i64),
+    Bool(bool),
+}
+
+/// A single header keyword record
+pub struct HeaderCard {
+    pub key: String,
+    pub value: HeaderValue,
+    pub comment: String,
+}
+
+impl HeaderCard {
+    pub fn new(key: &str, value: HeaderValue, comment: &str) -> Self {
+        HeaderCard {
+            key: key.to_uppercase(),
+            value,
+            comment: comment.to_owned(),
+        }
+    }
+
+    fn to_card(&self) -> String {
+        let value = match &self.value {
+            HeaderValue::Str(s) => format!("'{:<8}'", s.replace('\'', "''")),
+            HeaderValue::Float(f) => format!("{:>20}", format!("{:E}", f)),
+            HeaderValue::Int(i) => format!("{:>20}", i),
+            HeaderValue::Bool(b) => format!("{:>20}", if *b { "T" } else { "F" }),
+        };
+        let card = if self.comment.is_empty() {
+            format!("{:<8}= {}", self.key, value)
+        } else {
+            format!("{:<8}= {} / {}", self.key, value, self.comment)
+        };
+        format!("{:<80}", card.chars().take(CARD_SIZE).collect::<String>())
+    }
+}
+
+pub fn is_fits_file(file_path: &Path) -> bool {
+    matches!(
+        file_path
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .as_deref(),
+        Some("fits") | Some("fit") | Some("fts")
+    )
+}
+
+fn pad_to_block(buffer: &mut Vec<u8>, pad: u8) {
+    let rem = buffer.len() % BLOCK_SIZE;
+    if rem > 0 {
+        buffer.extend(std::iter::repeat(pad).take(BLOCK_SIZE - rem));
+    }
+}
+
+/// Writes an image as 32-bit floating point FITS without any normalization of the
+/// values. Multi-band images are written as a three dimensional array.
+pub fn save_image(image: &Image, file_path: &Path, cards: &[HeaderCard]) -> Result<()> {
+    let mut header = vec![
+        HeaderCard::new(
+            "SIMPLE",
+            HeaderValue::Bool(true),
+            "conforms to FITS standard",
+        ),
+        HeaderCard::new("BITPIX", HeaderValue::Int(-32), "32-bit floating point"),
+        HeaderCard::new(
+            "NAXIS",
+            HeaderValue::Int(if image.num_bands() > 1 { 3 } else { 2 }),
+            "",
+        ),
+        HeaderCard::new("NAXIS1", HeaderValue::Int(image.width as i64), ""),
+        HeaderCard::new("NAXIS2", HeaderValue::Int(image.height as i64), ""),
+    ];
+    if image.num_bands() > 1 {
+        header.push(HeaderCard::new(
+            "NAXIS3",
+            HeaderValue::Int(image.num_bands() as i64),
+            "",
+        ));
+    }
+
+    let mut bytes: Vec<u8> = vec![];
+    header
+        .iter()
+        .chain(cards.iter())
+        .for_each(|c| bytes.extend(c.to_card().as_bytes()));
+    bytes.extend(format!("{:<80}", "END").as_bytes());
+    pad_to_block(&mut bytes, b' ');
+
+    // FITS rows run from the bottom of the image up
+    (0..image.num_bands()).for_each(|b| {
+        let band = image.get_band(b);
+        iproduct!((0..image.height).rev(), 0..image.width).for_each(|(y, x)| {
+            bytes.extend(band.get(x, y).to_be_bytes());
+        });
+    });
+    pad_to_block(&mut bytes, 0);
+
+    let mut f = BufWriter::new(File::create(file_path)?);
+    f.write_all(&bytes)?;
+    Ok(())
+}
+
+/// Reads the primary image of a FITS file. Integer data is scaled with BZERO/BSCALE
+/// but otherwise left as stored.
+pub fn open_image(file_path: &Path) -> Result<Image> {
+    let mut bytes: Vec<u8> = vec![];
+    File::open(file_path)?.read_to_end(&mut bytes)?;
+
+    let mut bitpix: i32 = 0;
+    let mut naxis: Vec<usize> = vec![];
+    let mut bzero = 0.0;
+    let mut bscale = 1.0;
+    let mut data_start = 0;
+
+    for (i, card) in bytes.chunks(CARD_SIZE).enumerate() {
+        let card = String::from_utf8_lossy(card);
+        let key = card.get(0..8).unwrap_or("").trim().to_owned();
+        if key == "END" {
+            data_start = ((i + 1) * CARD_SIZE).div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+            break;
+        }
+        let value = card
+            .get(10..)
+            .unwrap_or("")
+            .split('/')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_owned();
+        match key.as_str() {
+            "BITPIX" => bitpix = value.parse()?,
+            "NAXIS" => naxis = vec![0; value.parse()?],
+            "BZERO" => bzero = value.parse()?,
+            "BSCALE" => bscale = value.parse()?,
+            k if k.starts_with("NAXIS") => {
+                let n: usize = k[5..].parse()?;
+                if n > 0 && n <= naxis.len() {
+                    naxis[n - 1] = value.parse()?;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if data_start == 0 || naxis.len() < 2 {
+        return Err(anyhow!("Unsupported or invalid FITS file: {:?}", file_path));
+    }
+
+    if ![8, 16, 32, -32, -64].contains(&bitpix) {
+        return Err(anyhow!("Unsupported FITS BITPIX value {}", bitpix));
+    }
+
+    let (width, height) = (naxis[0], naxis[1]);
+    let num_bands = if naxis.len() > 2 { naxis[2] } else { 1 };
+    let value_size = bitpix.unsigned_abs() as usize / 8;
+    let data_len = width * height * num_bands * value_size;
+    if bytes.len() < data_start + data_len {
+        return Err(anyhow!("FITS file is truncated: {:?}", file_path));
+    }
+
+    let data = &bytes[data_start..data_start + data_len];
+    let values: Vec<f32> = data
+        .chunks(value_size)
+        .map(|v| {
+            let raw = match bitpix {
+                8 => v[0] as f64,
+                16 => i16::from_be_bytes([v[0], v[1]]) as f64,
+                32 => i32::from_be_bytes([v[0], v[1], v[2], v[3]]) as f64,
+                -32 => f32::from_be_bytes([v[0], v[1], v[2], v[3]]) as f64,
+                -64 => f64::from_be_bytes([v[0], v[1], v[2], v[3], v[4], v[5], v[6], v[7]]),
+                _ => 0.0,
+            };
+            (bzero + bscale * raw) as f32
+        })
+        .collect();
+
+    // The first row stored is the bottom of the image
+    let mut image = Image::new_with_bands(width, height, num_bands, ImageMode::U16BIT)?;
+    (0..num_bands).for_each(|b| {
+        iproduct!(0..height, 0..width).for_each(|(y, x)| {
+            image.put(x, height - 1 - y, values[(b * height + y) * width + x], b);
+        });
+    });
+    Ok(image)
+}
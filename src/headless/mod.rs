@@ -0,0 +1,137 @@
+use anyhow::Result;
+use gtk::glib::{self, MainContext, Priority};
+use indicatif::{ProgressBar, ProgressStyle};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::output::{next_free_filename, OutputFormat, OverwritePolicy};
+use crate::process;
+use crate::state::*;
+use crate::task::TaskContext;
+use crate::taskstatus::*;
+
+///////////////////////////////////////////////////////
+/// Headless (command line) Processing
+///////////////////////////////////////////////////////
+
+/// Runs the full SolHat process using the parameters in `params_file` without building
+/// any of the user interface. Progress is reported to the terminal.
+pub async fn run_headless(
+    params_file: &Path,
+    output: Option<PathBuf>,
+    verbose: bool,
+) -> Result<glib::ExitCode> {
+    if verbose {
+        stump::set_min_log_level(stump::LogEntryLevel::DEBUG);
+    } else {
+        stump::set_min_log_level(stump::LogEntryLevel::WARN);
+    }
+
+    let mut state = ApplicationState {
+        params: ParametersState::load_from_file(params_file)?,
+        ..Default::default()
+    };
+    state.validate_paths();
+    if state.params.lights.is_empty() {
+        return Err(anyhow!(
+            "No valid light input specified in {:?}",
+            params_file
+        ));
+    }
+    let mut params = state.params;
+
+    // An explicit output filename decides the format by its extension
+    if let Some(format) = output.as_deref().and_then(OutputFormat::from_path) {
+        params.output_format = format;
+    }
+
+    let output_filename = match output {
+        Some(output) => output,
+        None => crate::assemble_output_filename(&params)?,
+    };
+    let output_filename = if output_filename.exists() {
+        match params.overwrite_policy {
+            OverwritePolicy::Overwrite => {
+                warn!("Overwriting existing output {:?}", output_filename);
+                output_filename
+            }
+            OverwritePolicy::Increment => next_free_filename(&output_filename, &[]),
+            OverwritePolicy::Prompt => {
+                return Err(anyhow!(
+                    "Output {:?} already exists. Set overwrite_policy to \"Overwrite\" or \"Increment\" to replace it or write beside it",
+                    output_filename
+                ));
+            }
+        }
+    } else {
+        output_filename
+    };
+    info!("Output will be written to {:?}", output_filename);
+
+    let pb = ProgressBar::new(0);
+    pb.set_style(
+        ProgressStyle::with_template("{msg:<40} [{bar:40}] {pos}/{len} ({eta})")
+            .unwrap()
+            .progress_chars("=> "),
+    );
+
+    // Route log output through the progress bar so the two don't garble each other.
+    let log_pb = pb.clone();
+    stump::set_print(move |s| {
+        log_pb.println(s);
+    });
+
+    let (process_sender, process_receiver) = MainContext::channel(Priority::default());
+
+    let status_pb = pb.clone();
+    process_receiver.attach(None, move |proc_status: TaskStatusContainer| {
+        match proc_status.status {
+            Some(TaskStatus::TaskPercentage(task_name, len, cnt)) => {
+                status_pb.set_message(task_name);
+                status_pb.set_length(len as u64);
+                status_pb.set_position(cnt as u64);
+            }
+            Some(TaskStatus::TaskFailed(why)) => {
+                status_pb.abandon_with_message(why);
+            }
+            Some(TaskStatus::TaskCancelled) => {
+                status_pb.abandon_with_message("Cancelled");
+            }
+            None => {
+                status_pb.finish_with_message("Done");
+            }
+        };
+        glib::Continue(true)
+    });
+
+    let process_task = TaskContext::new(params, process_sender);
+    let of = output_filename.clone();
+    let task = tokio::spawn(async move {
+        let result = process::run_async(process_task.clone(), None, of).await;
+        if result.is_ok() {
+            process_task.set_completed();
+        }
+        result
+    });
+
+    // There's no gtk application running the main loop for us, so we iterate it here
+    // until the processing task is done.
+    let main_context = MainContext::default();
+    while !task.is_finished() {
+        while main_context.iteration(false) {}
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    while main_context.iteration(false) {}
+
+    match task.await? {
+        Ok(_) => {
+            pb.println(format!("Output written to {:?}", output_filename));
+            Ok(glib::ExitCode::SUCCESS)
+        }
+        Err(why) => {
+            pb.abandon_with_message("Failed");
+            eprintln!("Processing failed: {}", why);
+            Ok(glib::ExitCode::FAILURE)
+        }
+    }
+}
@@ -1,6 +1,5 @@
 #[macro_use]
 mod state;
-use gtk::gdk_pixbuf::PixbufLoader;
 use state::*;
 
 mod cancel;
@@ -9,7 +8,11 @@ use cancel::*;
 mod taskstatus;
 use taskstatus::*;
 
+mod task;
+use task::TaskContext;
+
 mod analysis;
+use analysis::quality::{QualityMetric, RegionCombine};
 use analysis::*;
 
 mod process;
@@ -17,19 +20,53 @@ mod process;
 mod conversion;
 use conversion::*;
 
+mod headless;
+
+mod metadata;
+use metadata::*;
+
+mod profiles;
+use profiles::*;
+
+mod queue;
+use queue::*;
+
+mod fits;
+
+mod calibration;
+use calibration::*;
+
+mod viewer;
+
+mod deconvolve;
+use deconvolve::{DeconvolutionParameters, PsfShape};
+
+mod output;
+use output::pattern::{expand_pattern, PatternValues};
+use output::{is_output_taken, next_free_filename, OutputFormat, OverwritePolicy};
+
+mod sharpen;
+use sharpen::{SharpenMethod, SharpenParameters};
+
 use anyhow::Result;
+use clap::Parser;
 use gtk::gdk::Display;
-use gtk::glib::{MainContext, Priority, Type};
+use gtk::glib::{MainContext, Priority, Sender, Type};
 #[allow(deprecated)]
 use gtk::{
-    gio, prelude::*, Adjustment, ComboBoxText, CssProvider, Entry, Label, Picture, ProgressBar,
+    gio, prelude::*, Adjustment, ComboBoxText, CssProvider, Entry, Label, ProgressBar,
     ScrolledWindow, SpinButton, TextBuffer, STYLE_PROVIDER_PRIORITY_APPLICATION,
 };
-use gtk::{glib, AlertDialog, Application, ApplicationWindow, Builder, Button, CheckButton, Notebook};
+use gtk::pango::EllipsizeMode;
+use gtk::{glib, AlertDialog, Application, ApplicationWindow, Builder, Button, CheckButton, ListBox, Notebook, Orientation};
 use solhat::drizzle::Scale;
 use solhat::target::Target;
-use std::ffi::OsStr;
+use sciimg::prelude::Image;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
 use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use std::thread;
 use solhat::ser::SerFile;
 
@@ -46,9 +83,76 @@ const TAB_ID_FLAT:i32 = 2;
 const TAB_ID_FLATDARK:i32 = 3;
 const TAB_ID_BIAS:i32 = 4;
 const TAB_ID_ANALYSIS:i32 = 5;
+const TAB_ID_QUEUE:i32 = 6;
+const TAB_ID_RESULT:i32 = 7;
+
+/// Width and height of the region used to preview sharpening
+const SHARPEN_PREVIEW_SIZE: usize = 512;
+
+/// The most recently stacked output, shown in the Result tab
+struct StackResult {
+    image: Image,
+    output_filename: PathBuf,
+    first_light: Option<PathBuf>,
+}
+
+/// A task started from the user interface that hasn't finished yet
+struct RunningTask {
+    /// What was started, such as "Analysis", so its progress can be told apart
+    name: String,
+    cancel: CancelToken,
+    /// The last stage reported, so a failure can say where it happened
+    stage: String,
+    /// Given back to the user when the task finishes
+    button: Option<Button>,
+}
+
+impl RunningTask {
+    /// Text for the task status label
+    fn label(&self) -> String {
+        if self.stage.is_empty() {
+            self.name.to_owned()
+        } else {
+            format!("{}: {}", self.name, self.stage)
+        }
+    }
+}
+
+/// Running tasks by task id. The progress bar shows one of them at a time, and the
+/// cancel button only cancels that one.
+#[derive(Default)]
+struct TaskMonitor {
+    tasks: HashMap<usize, RunningTask>,
+    /// The task the progress bar and status label show, the latest started by default
+    shown: Option<usize>,
+}
+
+type TaskRegistry = Rc<RefCell<TaskMonitor>>;
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    /// Process without the user interface, using parameters from a TOML file
+    #[arg(long, value_name = "PARAMS_TOML")]
+    headless: Option<PathBuf>,
+
+    /// Output image path (headless only). Defaults to the assembled output filename
+    #[arg(short, long, requires = "headless")]
+    output: Option<PathBuf>,
+
+    /// Show verbose log output (headless only)
+    #[arg(short, long, requires = "headless")]
+    verbose: bool,
+}
 
 #[tokio::main]
 async fn main() -> Result<glib::ExitCode> {
+    let args = Cli::parse_from(wild::args());
+
+    if let Some(params_file) = &args.headless {
+        return headless::run_headless(params_file, args.output, args.verbose).await;
+    }
+
     stump::set_min_log_level(stump::LogEntryLevel::DEBUG);
     info!("Starting SolHat-UI");
 
@@ -56,7 +160,9 @@ async fn main() -> Result<glib::ExitCode> {
 
     application.connect_activate(build_styles);
     application.connect_activate(build_ui);
-    let exitcode = application.run();
+
+    // Our arguments have already been handled by clap, so gtk only gets the program name.
+    let exitcode = application.run_with_args(&wild::args().take(1).collect::<Vec<String>>());
 
     STATE.lock().unwrap().save_to_userhome()?;
     Ok(exitcode)
@@ -75,19 +181,17 @@ macro_rules! bind_object {
 macro_rules! update_output_filename {
     ($builder:expr) => {
         let lbl_output_filename: Label = bind_object!($builder, "lbl_output_filename");
-        lbl_output_filename.set_label(
-            assemble_output_filename()
-                .unwrap()
-                .file_name()
-                .unwrap()
-                .to_str()
-                .unwrap(),
-        );
+        let params = STATE.lock().unwrap().params.clone();
+        match assemble_output_filename(&params) {
+            Ok(output_filename) => lbl_output_filename
+                .set_label(output_filename.file_name().unwrap().to_str().unwrap()),
+            Err(why) => lbl_output_filename.set_label(&format!("Invalid pattern: {}", why)),
+        };
     };
 }
 
 macro_rules! update_preview_from_ser_file {
-    ($builder:expr,$ser_file_path:expr, $preview_id:expr) => {
+    ($ser_file_path:expr, $preview_id:expr) => {
         
 
         if let Some(ext) = $ser_file_path.extension() {
@@ -96,32 +200,44 @@ macro_rules! update_preview_from_ser_file {
 
                     let (pix_sender, pix_receiver) = MainContext::channel(Priority::default());
 
+                    let ser_file_path = $ser_file_path.to_owned();
+                    let sp = ser_file_path.clone();
                     thread::spawn(move || {
                         // Load the ser file, grab the first frame, then send it over to the message loop
-                        let ser_file = SerFile::load_ser($ser_file_path.to_str().unwrap()).unwrap();
+                        let ser_file = SerFile::load_ser(sp.to_str().unwrap()).unwrap();
                         let first_image = ser_file.get_frame(0).unwrap();
-                        pix_sender.send(Some(first_image)).expect("Failed to send pixbuf through channel");
+                        pix_sender.send(Some((first_image, ser_file.frame_count))).expect("Failed to send pixbuf through channel");
                     });
 
-                    let b = $builder.clone();
-                    pix_receiver.attach(
-                        None,
-                        glib::clone!( @weak b as builder => @default-return Continue(false),
-                                    move |pix_opt| {
-
-                                        if let Some(ser_frame) = pix_opt {
-                                            // If it's a valid image (and not None), convert it to
-                                            // gtk::Picture and display it
-                                            let pix = ser_frame_to_picture(&ser_frame).unwrap();
-                                            let pic: Picture = bind_object!(builder, $preview_id);
-                                            pic.set_pixbuf(Some(&pix));
-                                        }else {
-                                            error!("Failed to load preview image");
-                                        }
-                                        Continue(true)
-                                    }
-                                ),
-                            );
+                    pix_receiver.attach(None, move |pix_opt| {
+                        if let Some((ser_frame, frame_count)) = pix_opt {
+                            // If it's a valid image (and not None), show it in
+                            // the preview viewer with the rest of the frames available to browse
+                            viewer::set_preview_ser_frame($preview_id, &ser_file_path, frame_count, &ser_frame);
+                        }else {
+                            error!("Failed to load preview image");
+                        }
+                        Continue(true)
+                    });
+                }
+                "fits" | "fit" | "fts" | "tif" | "tiff" => {
+
+                    let (pix_sender, pix_receiver) = MainContext::channel(Priority::default());
+
+                    thread::spawn(move || {
+                        // Precomputed master calibration frame
+                        let master = open_image_file(&$ser_file_path).ok();
+                        pix_sender.send(master).expect("Failed to send pixbuf through channel");
+                    });
+
+                    pix_receiver.attach(None, move |img_opt| {
+                        if let Some(image) = img_opt {
+                            viewer::set_preview_image($preview_id, &image);
+                        } else {
+                            error!("Failed to load preview image");
+                        }
+                        Continue(true)
+                    });
                 }
                 _ => {
                     error!("User loaded an invalid ser file: {:?}", $ser_file_path);
@@ -169,7 +285,7 @@ macro_rules! bind_open_clear {
                 // is to provide a minimal calibration as data is identified prior to showing
                 // the preview to the user
                 if !$preview_id.is_empty()  {
-                    update_preview_from_ser_file!(builder, f, $preview_id);
+                    update_preview_from_ser_file!(f, $preview_id);
                 }
 
                 // Set tab_id if no tab is needed (such as for non image files)
@@ -205,10 +321,24 @@ macro_rules! bind_spinner {
     };
 }
 
+macro_rules! bind_combine_method {
+    ($builder:expr, $obj_id:expr, $state_prop:ident) => {
+        let combo: ComboBoxText = bind_object!($builder, $obj_id);
+        combo.set_active_id(Some(get_state_param!($state_prop).id()));
+        combo.connect_changed(|e| {
+            let method = CombineMethod::from_id(e.active_id().unwrap().as_str());
+            info!("Combine method with id {} set to {:?}", $obj_id, method);
+            set_state_param!($state_prop, method);
+        });
+    };
+}
+
 macro_rules! set_execute_enabled {
     ($builder:expr,$enabled:expr) => {
         let start: Button = bind_object!($builder, "btn_execute");
         start.set_sensitive($enabled);
+        let enqueue: Button = bind_object!($builder, "btn_enqueue");
+        enqueue.set_sensitive($enabled);
     };
 }
 
@@ -216,7 +346,7 @@ macro_rules! update_execute_state {
     ($builder:expr) => {
         set_execute_enabled!(
             $builder,
-            get_state_param!(light).is_some() && get_state_param!(output_dir).is_some()
+            !get_state_param!(lights).is_empty() && get_state_param!(output_dir).is_some()
         );
     };
 }
@@ -256,6 +386,10 @@ fn build_ui(application: &Application) {
         warn!("No saved state file found. One will be created on exit");
     }
 
+    if let Ok(profiles) = ProfileLibrary::load_from_userhome() {
+        STATE.lock().unwrap().profiles = profiles;
+    }
+
     let (process_sender, process_receiver) = MainContext::channel(Priority::default());
 
     let window: ApplicationWindow = builder
@@ -263,17 +397,53 @@ fn build_ui(application: &Application) {
         .expect("Couldn't get window");
     window.set_application(Some(application));
 
-    bind_open_clear!(
-        builder,
-        window,
-        "btn_light_open",
-        "btn_light_clear",
-        "lbl_light",
-        "img_preview_light",
-        light,
-        open_ser_file,
-        TAB_ID_LIGHT
-    );
+    [
+        "preview_light",
+        "preview_dark",
+        "preview_flat",
+        "preview_darkflat",
+        "preview_bias",
+        "preview_result",
+        "preview_sharpen",
+    ]
+    .iter()
+    .for_each(|preview_id| viewer::attach_viewer(&builder, preview_id));
+
+    ////////
+    // Light inputs
+    ////////
+    let btn_light_open: Button = bind_object!(builder, "btn_light_open");
+    let btn_light_clear: Button = bind_object!(builder, "btn_light_clear");
+    refresh_light_list(&builder);
+
+    let b = builder.clone();
+    btn_light_open.connect_clicked(glib::clone!(@weak window, @weak b as builder => move |_| {
+        debug!("Opening light files");
+        let lights = get_state_param!(lights);
+        let last_opened = if let Some(p) = lights.last() {
+            Some(p.to_owned())
+        } else {
+            get_last_opened_folder!()
+        };
+
+        open_ser_files("Open Light Files", &window, last_opened, glib::clone!(@weak builder => move |files| {
+            debug!("Opened: {:?}", files);
+            if let Some(f) = files.first() {
+                set_last_opened_folder!(f.parent().unwrap().to_owned());
+            }
+            STATE.lock().unwrap().params.lights.extend(files);
+            on_lights_changed(&builder);
+
+            let notebook : Notebook = bind_object!(builder, "notebook_previews");
+            notebook.set_page(TAB_ID_LIGHT);
+        }));
+    }));
+
+    let b = builder.clone();
+    btn_light_clear.connect_clicked(glib::clone!(@weak b as builder => move |_| {
+        set_state_param!(lights, vec![]);
+        on_lights_changed(&builder);
+    }));
 
     bind_open_clear!(
         builder,
@@ -281,9 +451,9 @@ fn build_ui(application: &Application) {
         "btn_dark_open",
         "btn_dark_clear",
         "lbl_dark",
-        "img_preview_dark",
+        "preview_dark",
         dark,
-        open_ser_file,
+        open_calibration_file,
         TAB_ID_DARK
     );
 
@@ -293,9 +463,9 @@ fn build_ui(application: &Application) {
         "btn_flat_open",
         "btn_flat_clear",
         "lbl_flat",
-        "img_preview_flat",
+        "preview_flat",
         flat,
-        open_ser_file,
+        open_calibration_file,
         TAB_ID_FLAT
     );
 
@@ -305,9 +475,9 @@ fn build_ui(application: &Application) {
         "btn_darkflat_open",
         "btn_darkflat_clear",
         "lbl_darkflat",
-        "img_preview_darkflat",
+        "preview_darkflat",
         darkflat,
-        open_ser_file,
+        open_calibration_file,
         TAB_ID_FLATDARK
     );
 
@@ -317,9 +487,9 @@ fn build_ui(application: &Application) {
         "btn_bias_open",
         "btn_bias_clear",
         "lbl_bias",
-        "img_preview_bias",
+        "preview_bias",
         bias,
-        open_ser_file,
+        open_calibration_file,
         TAB_ID_BIAS
     );
 
@@ -335,6 +505,95 @@ fn build_ui(application: &Application) {
         -1
     );
 
+    ////////
+    // Calibration masters
+    ////////
+    bind_combine_method!(builder, "combo_dark_method", dark_method);
+    bind_combine_method!(builder, "combo_flat_method", flat_method);
+    bind_combine_method!(builder, "combo_darkflat_method", darkflat_method);
+    bind_combine_method!(builder, "combo_bias_method", bias_method);
+
+    let chk_save_masters: CheckButton = bind_object!(builder, "chk_save_masters");
+    chk_save_masters.set_active(get_state_param!(save_masters));
+    chk_save_masters.connect_toggled(|e| {
+        set_state_param!(save_masters, e.is_active());
+    });
+
+    let combo_master_format: ComboBoxText = bind_object!(builder, "combo_master_format");
+    match get_state_param!(master_format) {
+        MasterFormat::Fits => combo_master_format.set_active_id(Some("Fits")),
+        MasterFormat::Tiff => combo_master_format.set_active_id(Some("Tiff")),
+    };
+    combo_master_format.connect_changed(|e| {
+        let format = match e.active_id().unwrap().as_str() {
+            "Tiff" => MasterFormat::Tiff,
+            _ => MasterFormat::Fits,
+        };
+        set_state_param!(master_format, format);
+    });
+
+    let btn_calibration_library: Button = bind_object!(builder, "btn_calibration_library");
+    let b = builder.clone();
+    btn_calibration_library.connect_clicked(glib::clone!(@weak window, @weak b as builder => move |_| {
+        let first_light = get_state_param!(lights).first().cloned();
+        let first_light = if let Some(f) = first_light {
+            f
+        } else {
+            show_error_dialog(&window, "Open a light input before looking for library masters");
+            return;
+        };
+
+        let key = match CameraKey::from_ser_file(&first_light) {
+            Ok(key) => key,
+            Err(why) => {
+                error!("Failed to read camera settings from {:?}: {:?}", first_light, why);
+                show_error_dialog(&window, &why.to_string());
+                return;
+            }
+        };
+        info!("Looking for library masters matching {:?}", key);
+
+        let library = match CalibrationLibrary::load_from_userhome() {
+            Ok(library) => library,
+            Err(why) => {
+                error!("Failed to load calibration library: {:?}", why);
+                show_error_dialog(&window, &why.to_string());
+                return;
+            }
+        };
+
+        let mut summary = vec![];
+        [
+            CalibrationKind::Flat,
+            CalibrationKind::DarkFlat,
+            CalibrationKind::Dark,
+            CalibrationKind::Bias,
+        ]
+        .into_iter()
+        .for_each(|kind| {
+            if let Some(master) = library.find(kind, &key) {
+                let path = Some(master.path.to_owned());
+                match kind {
+                    CalibrationKind::Flat => { set_state_param!(flat, path); }
+                    CalibrationKind::DarkFlat => { set_state_param!(darkflat, path); }
+                    CalibrationKind::Dark => { set_state_param!(dark, path); }
+                    CalibrationKind::Bias => { set_state_param!(bias, path); }
+                };
+                summary.push(format!("{}: {}", kind, master.path.file_name().unwrap().to_string_lossy()));
+            } else {
+                summary.push(format!("{}: No matching master", kind));
+            }
+        });
+        refresh_ui_from_state(&builder);
+
+        let info_dialog = AlertDialog::builder()
+            .modal(true)
+            .message("Library Masters")
+            .detail(summary.join("\n"))
+            .build();
+        info_dialog.show(Some(&window));
+    }));
+
     ////////
     // Output folder
     ////////
@@ -360,6 +619,77 @@ fn build_ui(application: &Application) {
         }),
     );
 
+    ////////
+    // Output filename pattern
+    ////////
+    let txt_output_pattern: Entry = bind_object!(builder, "txt_output_pattern");
+    txt_output_pattern.set_text(&get_state_param!(output_pattern));
+    txt_output_pattern.set_tooltip_text(Some(&format!(
+        "Tokens: {}",
+        output::pattern::TOKENS.map(|t| format!("{{{}}}", t)).join(" ")
+    )));
+    txt_output_pattern.connect_changed(glib::clone!(@weak b as builder => move |e| {
+        set_state_param!(output_pattern, e.text().to_string());
+        update_output_filename!(builder);
+    }));
+
+    ////////
+    // Output format
+    ////////
+    let combo_output_format: ComboBoxText = bind_object!(builder, "combo_output_format");
+    combo_output_format.set_active_id(Some(get_state_param!(output_format).id()));
+    combo_output_format.connect_changed(glib::clone!(@weak b as builder => move |e| {
+        let format = OutputFormat::from_id(e.active_id().unwrap().as_str());
+        info!("Output format set to {:?}", format);
+        set_state_param!(output_format, format);
+        update_output_filename!(builder);
+    }));
+
+    let combo_overwrite_policy: ComboBoxText = bind_object!(builder, "combo_overwrite_policy");
+    combo_overwrite_policy.set_active_id(Some(get_state_param!(overwrite_policy).id()));
+    combo_overwrite_policy.connect_changed(|e| {
+        let policy = OverwritePolicy::from_id(e.active_id().unwrap().as_str());
+        info!("Overwrite policy set to {:?}", policy);
+        set_state_param!(overwrite_policy, policy);
+    });
+
+    ////////
+    // Load settings from a previous output
+    ////////
+    let btn_load_from_output: Button = bind_object!(builder, "btn_load_from_output");
+    let b = builder.clone();
+    btn_load_from_output.connect_clicked(glib::clone!(@weak window, @weak b as builder => move |_| {
+        open_output_file("Load Settings From Output", &window, get_state_param!(output_dir), glib::clone!(@weak window, @weak builder => move |f| {
+            debug!("Loading settings from output: {:?}", f);
+            match RunMetadata::load_sidecar(&f) {
+                Ok(metadata) => {
+                    let params = metadata.to_parameters_state();
+                    let missing: Vec<String> = params
+                        .input_paths()
+                        .iter()
+                        .filter(|p| !p.exists())
+                        .map(|p| p.to_string_lossy().to_string())
+                        .collect();
+                    {
+                        let mut state = STATE.lock().unwrap();
+                        state.params = params;
+                        state.validate_paths();
+                    }
+                    refresh_ui_from_state(&builder);
+
+                    if !missing.is_empty() {
+                        warn!("Inputs from previous run no longer exist: {:?}", missing);
+                        show_error_dialog(&window, &format!("The following inputs no longer exist and have been cleared:\n{}", missing.join("\n")));
+                    }
+                }
+                Err(why) => {
+                    error!("Failed to load run metadata: {:?}", why);
+                    show_error_dialog(&window, &why.to_string());
+                }
+            }
+        }));
+    }));
+
     ////////
     // Free text
     ////////
@@ -433,6 +763,49 @@ fn build_ui(application: &Application) {
     bind_spinner!(builder, "spn_top_percentage", top_percentage, f64, true);
     bind_spinner!(builder, "spn_window_size", analysis_window_size, usize, true);
 
+    let combo_quality_metric: ComboBoxText = bind_object!(builder, "combo_quality_metric");
+    combo_quality_metric.set_active_id(Some(get_state_param!(quality_metric).id()));
+    combo_quality_metric.connect_changed(|e| {
+        let metric = QualityMetric::from_id(e.active_id().unwrap().as_str());
+        info!("Quality metric set to {:?}", metric);
+        set_state_param!(quality_metric, metric);
+    });
+
+    let combo_region_combine: ComboBoxText = bind_object!(builder, "combo_region_combine");
+    combo_region_combine.set_active_id(Some(get_state_param!(region_combine).id()));
+    combo_region_combine.connect_changed(|e| {
+        let combine = RegionCombine::from_id(e.active_id().unwrap().as_str());
+        info!("Analysis window combine set to {:?}", combine);
+        set_state_param!(region_combine, combine);
+    });
+
+    // Analysis windows are placed on the light preview, from the disk center found
+    // with the object detection threshold
+    let params = STATE.lock().unwrap().params.clone();
+    viewer::enable_analysis_windows("preview_light", &params, |regions| {
+        info!("Analysis windows set to {:?}", regions);
+        set_state_param!(analysis_regions, regions.to_vec());
+    });
+    ["spn_obj_detection_threshold", "spn_window_size"]
+        .iter()
+        .for_each(|spn_id| {
+            let spn_obj: SpinButton = bind_object!(builder, *spn_id);
+            spn_obj.adjustment().connect_value_changed(|_| {
+                let params = STATE.lock().unwrap().params.clone();
+                viewer::set_analysis_windows("preview_light", &params);
+            });
+        });
+
+    // The threshold and top percentage can be part of the output filename
+    ["spn_obj_detection_threshold", "spn_top_percentage"]
+        .iter()
+        .for_each(|spn_id| {
+            let spn_obj: SpinButton = bind_object!(builder, *spn_id);
+            spn_obj.adjustment().connect_value_changed(glib::clone!(@weak b as builder => move |_| {
+                update_output_filename!(builder);
+            }));
+        });
+
     ////////
     // Decorrelated Colors
     ////////
@@ -462,6 +835,143 @@ fn build_ui(application: &Application) {
     }));
     
 
+    ////////
+    // Profiles
+    ////////
+    let combo_profile: ComboBoxText = bind_object!(builder, "combo_profile");
+    let txt_profile_name: Entry = bind_object!(builder, "txt_profile_name");
+    refresh_profile_list(&builder);
+    combo_profile.connect_changed(glib::clone!(@weak b as builder => move |e| {
+        // Only apply the profile values when the user switches profiles, not when the
+        // list is rebuilt after a rename, duplicate, etc.
+        if let Some(name) = e.active_id() {
+            let profile = {
+                let mut state = STATE.lock().unwrap();
+                if state.profiles.active.as_deref() == Some(name.as_str()) {
+                    return;
+                }
+                state.profiles.set_active(name.as_str()).ok();
+                state.profiles.get_active().cloned()
+            };
+            if let Some(profile) = profile {
+                info!("Switching to profile '{}'", profile.name);
+                let txt_profile_name: Entry = bind_object!(builder, "txt_profile_name");
+                txt_profile_name.set_text(&profile.name);
+                apply_profile_to_ui(&builder, &profile);
+            }
+        }
+    }));
+
+    let btn_profile_new: Button = bind_object!(builder, "btn_profile_new");
+    btn_profile_new.connect_clicked(glib::clone!(@weak window, @weak txt_profile_name, @weak b as builder => move |_| {
+        let result = {
+            let mut state = STATE.lock().unwrap();
+            let name = if txt_profile_name.text().trim().is_empty() {
+                state.profiles.unique_name("Profile")
+            } else {
+                txt_profile_name.text().trim().to_string()
+            };
+            let params = state.params.clone();
+            state.profiles.create(&name, &params)
+        };
+        match result {
+            Ok(_) => {
+                save_profiles(&window);
+                refresh_profile_list(&builder);
+            }
+            Err(why) => show_error_dialog(&window, &why.to_string()),
+        }
+    }));
+
+    let btn_profile_save: Button = bind_object!(builder, "btn_profile_save");
+    btn_profile_save.connect_clicked(glib::clone!(@weak window, @weak b as builder => move |_| {
+        let result = {
+            let mut state = STATE.lock().unwrap();
+            let params = state.params.clone();
+            if let Some(active) = state.profiles.active.to_owned() {
+                info!("Saving current values to profile '{}'", active);
+                state.profiles.update(&active, &params)
+            } else {
+                let name = state.profiles.unique_name("Profile");
+                state.profiles.create(&name, &params)
+            }
+        };
+        match result {
+            Ok(_) => {
+                save_profiles(&window);
+                refresh_profile_list(&builder);
+            }
+            Err(why) => show_error_dialog(&window, &why.to_string()),
+        }
+    }));
+
+    let btn_profile_rename: Button = bind_object!(builder, "btn_profile_rename");
+    btn_profile_rename.connect_clicked(glib::clone!(@weak window, @weak txt_profile_name, @weak b as builder => move |_| {
+        let result = {
+            let mut state = STATE.lock().unwrap();
+            if let Some(active) = state.profiles.active.to_owned() {
+                state.profiles.rename(&active, txt_profile_name.text().trim())
+            } else {
+                Err(anyhow!("No profile selected"))
+            }
+        };
+        match result {
+            Ok(_) => {
+                save_profiles(&window);
+                refresh_profile_list(&builder);
+            }
+            Err(why) => show_error_dialog(&window, &why.to_string()),
+        }
+    }));
+
+    let btn_profile_duplicate: Button = bind_object!(builder, "btn_profile_duplicate");
+    btn_profile_duplicate.connect_clicked(glib::clone!(@weak window, @weak b as builder => move |_| {
+        let result = {
+            let mut state = STATE.lock().unwrap();
+            if let Some(active) = state.profiles.active.to_owned() {
+                state.profiles.duplicate(&active)
+            } else {
+                Err(anyhow!("No profile selected"))
+            }
+        };
+        match result {
+            Ok(_) => {
+                save_profiles(&window);
+                refresh_profile_list(&builder);
+            }
+            Err(why) => show_error_dialog(&window, &why.to_string()),
+        }
+    }));
+
+    let btn_profile_delete: Button = bind_object!(builder, "btn_profile_delete");
+    btn_profile_delete.connect_clicked(glib::clone!(@weak window, @weak b as builder => move |_| {
+        let result = {
+            let mut state = STATE.lock().unwrap();
+            if let Some(active) = state.profiles.active.to_owned() {
+                info!("Deleting profile '{}'", active);
+                state.profiles.delete(&active)
+            } else {
+                Err(anyhow!("No profile selected"))
+            }
+        };
+        match result {
+            Ok(_) => {
+                save_profiles(&window);
+                refresh_profile_list(&builder);
+                // Deleting the active profile switches to another, so bring its values in
+                let profile = STATE.lock().unwrap().profiles.get_active().cloned();
+                if let Some(profile) = profile {
+                    apply_profile_to_ui(&builder, &profile);
+                }
+            }
+            Err(why) => show_error_dialog(&window, &why.to_string()),
+        }
+    }));
+
+    // Each task gets its own parameters, cancel token and progress, so the threshold
+    // test, analysis and processing can run at the same time.
+    let running_tasks: TaskRegistry = Rc::new(RefCell::new(TaskMonitor::default()));
+
     ////////
     // Threshold Test
     ////////
@@ -469,19 +979,26 @@ fn build_ui(application: &Application) {
     let (stat_sender, stat_receiver) = MainContext::channel(Priority::default());
     let (pix_sender, pix_receiver) = MainContext::channel(Priority::default());
     let ps = process_sender.clone();
-    btn_thresh_test.connect_clicked(glib::clone!(@weak window, @weak b as builder => move |_| {
+    btn_thresh_test.connect_clicked(glib::clone!(@weak window, @weak b as builder, @strong running_tasks => move |_| {
         info!("Thresh test clicked");
         let stat_sender = stat_sender.clone();
         let pix_sender = pix_sender.clone();
-        let ps =  ps.clone();
+        let task = start_task(&running_tasks, &ps, "Threshold Test", None);
 
         thread::spawn(move || {
             stat_sender.send(false).expect("Could not send through channel");
-            if let Ok(buffer) = threshold::run_thresh_test(ps) {
-                pix_sender.send(Some(buffer)).expect("Failed to send pixbuf through channel");
-            } else {
-                pix_sender.send(None).expect("Failed to send pixbuf through channel");
-            }
+            // A panic in the test still reports the failure and gives the button back
+            match panic::catch_unwind(AssertUnwindSafe(|| threshold::run_thresh_test(&task))) {
+                Ok(Ok(buffer)) => pix_sender.send(buffer).expect("Failed to send pixbuf through channel"),
+                Ok(Err(why)) => {
+                    error!("Threshold test failed: {:?}", why);
+                    task.set_failed(&why.to_string());
+                }
+                Err(_) => {
+                    error!("Threshold test panicked");
+                    task.set_failed("Threshold test stopped unexpectedly. See the log for details.");
+                }
+            };
             stat_sender.send(true).expect("Could not send through channel");
         });
 
@@ -497,24 +1014,10 @@ fn build_ui(application: &Application) {
     );
     pix_receiver.attach(
         None,
-        glib::clone!(@weak window, @weak b as builder => @default-return Continue(false),
-                    move |buffer_opt| {
-                        if let Some(buffer) = buffer_opt {
-                            let pix = image_to_picture(&buffer).expect("Failed to convert imagebuffer to pixbuf");
-                            let pic: Picture = bind_object!(builder, "img_preview_light");
-                            pic.set_pixbuf(Some(&pix));
-                        } else {
-                            let info_dialog = AlertDialog::builder()
-                                                            .modal(true)
-                                                            .message("Error")
-                                                            .detail("No light input specified. Please do so before continuing")
-                                                            .build();
-
-                            info_dialog.show(Some(&window));
-                        }
-                        Continue(true)
-                    }
-        ),
+        move |buffer: Image| {
+            viewer::set_preview_image("preview_light", &buffer);
+            Continue(true)
+        },
     );
 
     ////////
@@ -524,21 +1027,25 @@ fn build_ui(application: &Application) {
     let (ana_stat_sender, ana_stat_receiver) = MainContext::channel(Priority::default());
     let (ana_data_sender, ana_data_receiver) = MainContext::channel(Priority::default());
     let ps = process_sender.clone();
-    btn_analysis.connect_clicked(glib::clone!(@weak window, @weak b as builder => move |_| {
+    btn_analysis.connect_clicked(glib::clone!(@weak window, @weak b as builder, @strong running_tasks => move |_| {
         info!("Analysis clicked");
         let stat_sender = ana_stat_sender.clone();
         let data_sender = ana_data_sender.clone();
-        let ps =  ps.clone();
+        let task = start_task(&running_tasks, &ps, "Analysis", None);
 
         thread::spawn(move || {
             stat_sender.send(false).expect("Could not send through channel");
-            match sigma::run_sigma_analysis(ps) {
-                Ok(data_series) => data_sender.send(Some(data_series)).expect("Failed to send pixbuf through channel"),
-                Err(TaskCompletion::Error(why)) => {
+            match panic::catch_unwind(AssertUnwindSafe(|| sigma::run_sigma_analysis(&task))) {
+                Ok(Ok(data_series)) => data_sender.send(data_series).expect("Failed to send pixbuf through channel"),
+                Ok(Err(TaskCompletion::Error(why))) => {
                     error!("Task error: {:?}", why);
-                    data_sender.send(None).expect("Failed to send pixbuf through channel")
+                    task.set_failed(&why);
                 },
-                Err(_) => {} // Ignore
+                Ok(Err(_)) => {} // Cancellation is reported by check_cancel_status
+                Err(_) => {
+                    error!("Analysis panicked");
+                    task.set_failed("Analysis stopped unexpectedly. See the log for details.");
+                }
             };
             stat_sender.send(true).expect("Could not send through channel");
         });
@@ -554,36 +1061,38 @@ fn build_ui(application: &Application) {
         ),
     );
 
+    let analysis_container: gtk::Box = bind_object!(builder, "analysis_chart");
+    // Dragging a limit on the chart sets it in the state, so the spinners follow along
+    let analysis_chart = chart::AnalysisChart::new(&analysis_container, glib::clone!(@weak b as builder => move || {
+        let params = STATE.lock().unwrap().params.clone();
+        let (min_sigma, max_sigma, top_percentage) = (params.min_sigma, params.max_sigma, params.top_percentage);
+        [("spn_min_sigma", min_sigma), ("spn_max_sigma", max_sigma), ("spn_top_percentage", top_percentage)]
+            .iter()
+            .for_each(|(spn_id, value)| {
+                let spn_obj: SpinButton = bind_object!(builder, *spn_id);
+                spn_obj.set_value(*value);
+            });
+    }));
+    ["spn_min_sigma", "spn_max_sigma", "spn_top_percentage", "spn_max_frames"]
+        .iter()
+        .for_each(|spn_id| {
+            let spn_obj: SpinButton = bind_object!(builder, *spn_id);
+            spn_obj.adjustment().connect_value_changed(glib::clone!(@strong analysis_chart => move |_| {
+                analysis_chart.refresh();
+            }));
+        });
     ana_data_receiver.attach(
         None,
-        glib::clone!(@weak window, @weak b as builder => @default-return Continue(false),
+        glib::clone!(@weak b as builder => @default-return Continue(false),
                     move |data_series| {
-                        if let Some(data_series) = &data_series {
-                            let pic: Picture = bind_object!(builder, "img_analysis");
-                            let pic_label : Label =  bind_object!(builder, "lbl_analysis");
-                            let notebook : Notebook = bind_object!(builder, "notebook_previews");
-                            
-                            // Try to find out the size dynamically. Currently, using
-                            // pic.width()/pic.height() don't work before it's been set to something.
-                            // Also, using notebook width/height seems sorta hackish.
-                            let svg_string = sigma::create_chart(data_series,notebook.width() as isize,notebook.height() as isize).unwrap();
-                            let loader = PixbufLoader::new();
-                            loader.write(svg_string.as_bytes()).expect("Failed to write svg to pixbuf loader");
-                            loader.close().expect("Failed to load svg");
-                            let pixbuf = loader.pixbuf().unwrap();
-                            pic.set_pixbuf(Some(&pixbuf));
-                            pic.set_visible(true);
-                            pic_label.set_visible(false);
-                            notebook.set_page(TAB_ID_ANALYSIS);
-                        } else {
-                            let info_dialog = AlertDialog::builder()
-                                                            .modal(true)
-                                                            .message("Error")
-                                                            .detail("Unable to perform sigma analysis")
-                                                            .build();
-
-                            info_dialog.show(Some(&window));
-                        }
+                        let pic_label : Label =  bind_object!(builder, "lbl_analysis");
+                        let notebook : Notebook = bind_object!(builder, "notebook_previews");
+
+                        // The chart is rendered at whatever size the view ends up being
+                        analysis_chart.set_series(data_series);
+                        analysis_container.set_visible(true);
+                        pic_label.set_visible(false);
+                        notebook.set_page(TAB_ID_ANALYSIS);
                         Continue(true)
                     }
         ),
@@ -605,63 +1114,445 @@ fn build_ui(application: &Application) {
     let progress: ProgressBar = bind_object!(b, "prg_task_progress");
     let cancel: Button = bind_object!(b, "btn_cancel");
 
-    cancel.connect_clicked(move |_| {
-        set_request_cancel();
-    });
+    // Only the task being shown is cancelled, others carry on
+    cancel.connect_clicked(glib::clone!(@strong running_tasks => move |_| {
+        let monitor = running_tasks.borrow();
+        if let Some(task) = monitor.shown.and_then(|id| monitor.tasks.get(&id)) {
+            task.cancel.request_cancel();
+        }
+    }));
 
     ////////
     // Process Execution
     ////////
 
     update_execute_state!(builder);
+    let (result_sender, result_receiver) = MainContext::channel(Priority::default());
+    let (preview_sender, preview_receiver) = MainContext::channel(Priority::default());
     let start: Button = bind_object!(builder, "btn_execute");
     #[allow(clippy::redundant_clone)]
     let ps = process_sender.clone();
-    start.connect_clicked(move |_| {
+    let pvs = preview_sender.clone();
+    let queue_result_sender = result_sender.clone();
+    start.connect_clicked(glib::clone!(@weak window, @strong running_tasks => move |btn| {
         debug!("Start has been clicked");
         let ps = ps.clone();
-        tokio::spawn(async move {
-            {
-                ps.send(TaskStatusContainer {
-                    status: Some(TaskStatus::TaskPercentage("Starting".to_owned(), 0, 0)),
-                })
-                .expect("Failed to sent task status");
-                process::run_async(ps, assemble_output_filename().unwrap()).await.unwrap(); //.await.unwrap();
+        let pvs = pvs.clone();
+        let rs = result_sender.clone();
+        let params = STATE.lock().unwrap().params.clone();
+        let output_filename = match assemble_output_filename(&params) {
+            Ok(output_filename) => output_filename,
+            Err(why) => {
+                show_error_dialog(&window, &why.to_string());
+                return;
             }
+        };
+        let btn = btn.clone();
+        let running_tasks = running_tasks.clone();
+        let queued: Vec<PathBuf> = QUEUE.lock().unwrap().jobs.iter().map(|j| j.output_filename.to_owned()).collect();
+        resolve_output_collision(&window, output_filename, queued, move |output_filename| {
+            let task = TaskContext::new(params, ps);
+            register_task(&running_tasks, &task, "Processing", Some(btn));
+            tokio::spawn(async move {
+                task.set_status("Starting", 0, 0);
+                let first_light = task.params.lights.first().cloned();
+
+                // Processing runs in its own task so even a panic gets reported
+                // and the controls are given back to the user
+                let process_task = task.clone();
+                let of = output_filename.clone();
+                let result = tokio::spawn(async move {
+                    process::run_async(process_task, Some(pvs), of).await
+                }).await;
+                match result {
+                    Ok(Ok(_)) => {
+                        task.set_completed();
+                        rs.send((output_filename, first_light)).expect("Failed to send result");
+                    },
+                    Ok(Err(why)) => match why.downcast_ref::<TaskCompletion>() {
+                        Some(TaskCompletion::Cancelled) => info!("Processing cancelled"),
+                        _ => {
+                            error!("Processing failed: {:?}", why);
+                            task.set_failed(&why.to_string());
+                        }
+                    },
+                    Err(why) => {
+                        error!("Processing task panicked: {:?}", why);
+                        task.set_failed("Processing stopped unexpectedly. See the log for details.");
+                    }
+                };
+            });
         });
+    }));
+
+    ////////
+    // Result
+    ////////
+    let last_result: Rc<RefCell<Option<StackResult>>> = Rc::new(RefCell::new(None));
+    // The last stack before sharpening
+    let last_stack: Rc<RefCell<Option<Image>>> = Rc::new(RefCell::new(None));
+    let lbl_result_file: Label = bind_object!(builder, "lbl_result_file");
+    let lbl_stack_progress: Label = bind_object!(builder, "lbl_stack_progress");
+    let btn_result_compare: gtk::ToggleButton = bind_object!(builder, "btn_result_compare");
+    let btn_result_open_folder: Button = bind_object!(builder, "btn_result_open_folder");
+
+    btn_result_compare.connect_toggled(glib::clone!(@strong last_result => move |btn| {
+        if let Some(result) = &*last_result.borrow() {
+            if !btn.is_active() {
+                viewer::set_preview_image("preview_result", &result.image);
+            } else if let Some(first_light) = &result.first_light {
+                match SerFile::load_ser(first_light.to_str().unwrap()) {
+                    Ok(ser_file) => match ser_file.get_frame(0) {
+                        Ok(frame) => viewer::set_preview_image("preview_result", &frame.buffer),
+                        Err(why) => error!("Failed to load first raw frame: {:?}", why),
+                    },
+                    Err(why) => error!("Failed to load ser file {:?}: {:?}", first_light, why),
+                }
+            }
+        }
+    }));
+
+    btn_result_open_folder.connect_clicked(glib::clone!(@weak window, @strong last_result => move |_| {
+        let output_file = match &*last_result.borrow() {
+            Some(result) => gio::File::for_path(&result.output_filename),
+            None => return,
+        };
+        let launcher = gtk::FileLauncher::new(Some(&output_file));
+        launcher.open_containing_folder(Some(&window), gio::Cancellable::NONE, |r| {
+            if let Err(why) = r {
+                error!("Failed to open output folder: {:?}", why);
+            }
+        });
+    }));
+
+    // Live preview of the stack as it's built. Switches to the Result tab at the start
+    // of each run.
+    let mut last_frames_stacked = usize::MAX;
+    preview_receiver.attach(
+        None,
+        glib::clone!(@weak b as builder, @weak lbl_stack_progress, @strong last_stack => @default-return Continue(false),
+            move |snapshot: process::StackSnapshot| {
+                viewer::set_preview_image("preview_result", &snapshot.image);
+                lbl_stack_progress.set_label(&format!("Stacking: {} of {} frames", snapshot.frames_stacked, snapshot.frame_count));
+                if snapshot.frames_stacked < last_frames_stacked {
+                    let notebook : Notebook = bind_object!(builder, "notebook_previews");
+                    notebook.set_page(TAB_ID_RESULT);
+                }
+                last_frames_stacked = snapshot.frames_stacked;
+                *last_stack.borrow_mut() = Some(snapshot.image);
+                Continue(true)
+            }
+        ),
+    );
+
+    result_receiver.attach(
+        None,
+        glib::clone!(@weak b as builder, @strong last_result => @default-return Continue(false),
+            move |(output_filename, first_light): (PathBuf, Option<PathBuf>)| {
+                match open_image_file(&output_filename) {
+                    Ok(image) => {
+                        *last_result.borrow_mut() = Some(StackResult {
+                            image,
+                            output_filename: output_filename.to_owned(),
+                            first_light: first_light.to_owned(),
+                        });
+                        btn_result_compare.set_active(false);
+                        btn_result_compare.set_sensitive(first_light.is_some());
+                        btn_result_open_folder.set_sensitive(true);
+                        lbl_result_file.set_label(output_filename.to_str().unwrap());
+                        lbl_stack_progress.set_label("");
+                        if let Some(result) = &*last_result.borrow() {
+                            viewer::set_preview_image("preview_result", &result.image);
+                        }
+
+                        let notebook : Notebook = bind_object!(builder, "notebook_previews");
+                        notebook.set_page(TAB_ID_RESULT);
+                    }
+                    Err(why) => error!("Failed to load result {:?}: {:?}", output_filename, why),
+                }
+                Continue(true)
+            }
+        ),
+    );
+
+    ////////
+    // Deconvolution
+    ////////
+    let deconvolution = get_state_param!(deconvolution);
+    refresh_deconvolution_controls(&builder, &deconvolution);
+
+    let chk_deconvolution_enabled: CheckButton = bind_object!(builder, "chk_deconvolution_enabled");
+    chk_deconvolution_enabled.connect_toggled(glib::clone!(@weak b as builder => move |e| {
+        STATE.lock().unwrap().params.deconvolution.enabled = e.is_active();
+        info!("Deconvolution: {}", e.is_active());
+        let deconvolution = get_state_param!(deconvolution);
+        refresh_deconvolution_controls(&builder, &deconvolution);
+    }));
+
+    let combo_deconvolution_psf: ComboBoxText = bind_object!(builder, "combo_deconvolution_psf");
+    combo_deconvolution_psf.connect_changed(glib::clone!(@weak b as builder => move |e| {
+        let psf = match e.active_id().unwrap().as_str() {
+            "Moffat" => PsfShape::Moffat,
+            _ => PsfShape::Gaussian,
+        };
+        STATE.lock().unwrap().params.deconvolution.psf = psf;
+        let deconvolution = get_state_param!(deconvolution);
+        refresh_deconvolution_controls(&builder, &deconvolution);
+    }));
+
+    let spn_deconvolution_fwhm: SpinButton = bind_object!(builder, "spn_deconvolution_fwhm");
+    spn_deconvolution_fwhm.adjustment().connect_value_changed(|e| {
+        STATE.lock().unwrap().params.deconvolution.fwhm = e.value();
+    });
+
+    let spn_deconvolution_beta: SpinButton = bind_object!(builder, "spn_deconvolution_beta");
+    spn_deconvolution_beta.adjustment().connect_value_changed(|e| {
+        STATE.lock().unwrap().params.deconvolution.moffat_beta = e.value();
+    });
+
+    let spn_deconvolution_iterations: SpinButton = bind_object!(builder, "spn_deconvolution_iterations");
+    spn_deconvolution_iterations.adjustment().connect_value_changed(|e| {
+        STATE.lock().unwrap().params.deconvolution.iterations = e.value() as usize;
+    });
+
+    ////////
+    // Sharpening
+    ////////
+    let sharpen_region: Rc<RefCell<Option<Image>>> = Rc::new(RefCell::new(None));
+    let btn_sharpen_original: gtk::ToggleButton = bind_object!(builder, "btn_sharpen_original");
+    let render_sharpen_preview: Rc<dyn Fn()> = Rc::new(glib::clone!(@strong sharpen_region, @weak btn_sharpen_original => move || {
+        if let Some(region) = &*sharpen_region.borrow() {
+            if btn_sharpen_original.is_active() {
+                viewer::set_preview_image("preview_sharpen", region);
+            } else {
+                let sharpening = get_state_param!(sharpening);
+                match sharpen::sharpen_image(region, &sharpening) {
+                    Ok(sharpened) => viewer::set_preview_image("preview_sharpen", &sharpened),
+                    Err(why) => error!("Failed to sharpen preview: {:?}", why),
+                }
+            }
+        }
+    }));
+    let sharpening = get_state_param!(sharpening);
+    refresh_sharpen_controls(&builder, &sharpening);
+
+    btn_sharpen_original.connect_toggled(glib::clone!(@strong render_sharpen_preview => move |_| {
+        render_sharpen_preview();
+    }));
+
+    let chk_sharpen_enabled: CheckButton = bind_object!(builder, "chk_sharpen_enabled");
+    chk_sharpen_enabled.connect_toggled(|e| {
+        STATE.lock().unwrap().params.sharpening.enabled = e.is_active();
+        info!("Sharpening: {}", e.is_active());
+    });
+
+    let combo_sharpen_method: ComboBoxText = bind_object!(builder, "combo_sharpen_method");
+    combo_sharpen_method.connect_changed(glib::clone!(@weak b as builder, @strong render_sharpen_preview => move |e| {
+        let method = match e.active_id().unwrap().as_str() {
+            "UnsharpMask" => SharpenMethod::UnsharpMask,
+            _ => SharpenMethod::Wavelets,
+        };
+        STATE.lock().unwrap().params.sharpening.method = method;
+        let sharpening = get_state_param!(sharpening);
+        refresh_sharpen_controls(&builder, &sharpening);
+        render_sharpen_preview();
+    }));
+
+    (0..sharpen::WAVELET_LAYERS).for_each(|n| {
+        let spn_gain: SpinButton = bind_object!(builder, &format!("spn_wavelet_gain_{}", n));
+        spn_gain.adjustment().connect_value_changed(glib::clone!(@strong render_sharpen_preview => move |e| {
+            if let Some(layer) = STATE.lock().unwrap().params.sharpening.layers.get_mut(n) {
+                layer.gain = e.value();
+            }
+            render_sharpen_preview();
+        }));
+
+        let spn_denoise: SpinButton = bind_object!(builder, &format!("spn_wavelet_denoise_{}", n));
+        spn_denoise.adjustment().connect_value_changed(glib::clone!(@strong render_sharpen_preview => move |e| {
+            if let Some(layer) = STATE.lock().unwrap().params.sharpening.layers.get_mut(n) {
+                layer.denoise = e.value();
+            }
+            render_sharpen_preview();
+        }));
     });
+
+    let spn_unsharp_radius: SpinButton = bind_object!(builder, "spn_unsharp_radius");
+    spn_unsharp_radius.adjustment().connect_value_changed(glib::clone!(@strong render_sharpen_preview => move |e| {
+        STATE.lock().unwrap().params.sharpening.unsharp_radius = e.value();
+        render_sharpen_preview();
+    }));
+
+    let spn_unsharp_amount: SpinButton = bind_object!(builder, "spn_unsharp_amount");
+    spn_unsharp_amount.adjustment().connect_value_changed(glib::clone!(@strong render_sharpen_preview => move |e| {
+        STATE.lock().unwrap().params.sharpening.unsharp_amount = e.value();
+        render_sharpen_preview();
+    }));
+
+    let btn_sharpen_reset: Button = bind_object!(builder, "btn_sharpen_reset");
+    btn_sharpen_reset.connect_clicked(glib::clone!(@weak b as builder => move |_| {
+        let enabled = get_state_param!(sharpening).enabled;
+        set_state_param!(sharpening, SharpenParameters { enabled, ..Default::default() });
+        let sharpening = get_state_param!(sharpening);
+        refresh_sharpen_controls(&builder, &sharpening);
+    }));
+
+    // Sharpening is previewed on a region of the stack, taken from wherever the Result
+    // view is centered, so the controls stay responsive on full size images.
+    let btn_sharpen_region: Button = bind_object!(builder, "btn_sharpen_region");
+    btn_sharpen_region.connect_clicked(glib::clone!(@weak window, @strong last_stack, @strong last_result, @strong sharpen_region, @strong render_sharpen_preview => move |_| {
+        let stack = if let Some(stack) = &*last_stack.borrow() {
+            stack.clone()
+        } else if let Some(result) = &*last_result.borrow() {
+            result.image.clone()
+        } else {
+            show_error_dialog(&window, "Run a stack before previewing sharpening");
+            return;
+        };
+
+        let (cx, cy) = viewer::preview_view_center("preview_result").unwrap_or((stack.width / 2, stack.height / 2));
+        let left = cx.saturating_sub(SHARPEN_PREVIEW_SIZE / 2);
+        let top = cy.saturating_sub(SHARPEN_PREVIEW_SIZE / 2);
+        match sharpen::crop_image(&stack, left, top, SHARPEN_PREVIEW_SIZE, SHARPEN_PREVIEW_SIZE) {
+            Ok(region) => {
+                *sharpen_region.borrow_mut() = Some(region);
+                render_sharpen_preview();
+            }
+            Err(why) => {
+                error!("Failed to crop sharpening preview region: {:?}", why);
+                show_error_dialog(&window, &why.to_string());
+            }
+        }
+    }));
+
+    ////////
+    // Job Queue
+    ////////
+    let (queue_sender, queue_receiver) = MainContext::channel(Priority::default());
+    let btn_enqueue: Button = bind_object!(builder, "btn_enqueue");
+    btn_enqueue.connect_clicked(glib::clone!(@weak window, @weak b as builder => move |_| {
+        let params = STATE.lock().unwrap().params.clone();
+        let output_filename = match assemble_output_filename(&params) {
+            Ok(output_filename) => output_filename,
+            Err(why) => {
+                show_error_dialog(&window, &why.to_string());
+                return;
+            }
+        };
+        let queued: Vec<PathBuf> = QUEUE.lock().unwrap().jobs.iter().map(|j| j.output_filename.to_owned()).collect();
+        resolve_output_collision(&window, output_filename, queued, glib::clone!(@weak builder => move |output_filename| {
+            QUEUE.lock().unwrap().enqueue(params, output_filename);
+            refresh_queue_list(&builder);
+
+            let notebook : Notebook = bind_object!(builder, "notebook_previews");
+            notebook.set_page(TAB_ID_QUEUE);
+        }));
+    }));
+
+    let btn_queue_run: Button = bind_object!(builder, "btn_queue_run");
+    let ps = process_sender.clone();
+    btn_queue_run.connect_clicked(glib::clone!(@strong running_tasks => move |btn| {
+        if QUEUE.lock().unwrap().running {
+            warn!("Job queue is already running");
+            return;
+        }
+        let task = start_task(&running_tasks, &ps, "Job Queue", Some(btn.clone()));
+        tokio::spawn(queue::run_queue(
+            task,
+            preview_sender.clone(),
+            queue_result_sender.clone(),
+            queue_sender.clone(),
+        ));
+    }));
+
+    let btn_queue_clear: Button = bind_object!(builder, "btn_queue_clear");
+    btn_queue_clear.connect_clicked(glib::clone!(@weak b as builder => move |_| {
+        QUEUE.lock().unwrap().clear_completed();
+        refresh_queue_list(&builder);
+    }));
+
+    queue_receiver.attach(
+        None,
+        glib::clone!(@weak b as builder => @default-return Continue(false),
+            move |_| {
+                refresh_queue_list(&builder);
+                Continue(true)
+            }
+        ),
+    );
+    refresh_queue_list(&builder);
+    let lbl_stack_progress: Label = bind_object!(builder, "lbl_stack_progress");
     process_receiver.attach(
         None,
-        glib::clone!(@weak label, @weak start, @weak btn_thresh_test, @weak btn_analysis => @default-return Continue(false),
-            move |proc_status| {
-                match &proc_status.status {
+        glib::clone!(@weak window, @weak label, @weak lbl_stack_progress, @strong running_tasks => @default-return Continue(false),
+            move |proc_status: TaskStatusContainer| {
+                let mut monitor = running_tasks.borrow_mut();
+
+                // Stragglers from a task that has already finished or been cancelled
+                let (name, stage) = match monitor.tasks.get(&proc_status.task_id) {
+                    Some(task) => (task.name.to_owned(), task.stage.to_owned()),
+                    None => return Continue(true),
+                };
+                let shown = monitor.shown == Some(proc_status.task_id);
+
+                let finished_status = match &proc_status.status {
                     Some(TaskStatus::TaskPercentage(task_name, len, cnt)) => {
-                        if *len > 0 {
-                            progress.set_fraction(*cnt as f64 / *len as f64);
-                        } else {
-                            progress.pulse();
-                        };
-                        // label.set_visible(true);
-                        progress.set_visible(true);
-                        cancel.set_visible(true);
-                        label.set_label(task_name);
-                        start.set_sensitive(false);
-                        cancel.set_sensitive(true);
-                        btn_thresh_test.set_sensitive(false);
-                        btn_analysis.set_sensitive(false);
+                        if let Some(task) = monitor.tasks.get_mut(&proc_status.task_id) {
+                            task.stage = task_name.to_owned();
+                            // Other tasks keep their stage for when they're shown
+                            if shown {
+                                if *len > 0 {
+                                    progress.set_fraction(*cnt as f64 / *len as f64);
+                                } else {
+                                    progress.pulse();
+                                };
+                                // label.set_visible(true);
+                                progress.set_visible(true);
+                                cancel.set_visible(true);
+                                label.set_label(&task.label());
+                                cancel.set_sensitive(true);
+                            }
+                        }
+                        None
+                    },
+                    Some(TaskStatus::TaskFailed(why)) => {
+                        let error_dialog = AlertDialog::builder()
+                            .modal(true)
+                            .message(format!("{} Failed During: {}", name, stage))
+                            .detail(why)
+                            .build();
+                        error_dialog.show(Some(&window));
+                        lbl_stack_progress.set_label("");
+                        Some(format!("{} Failed", name))
+                    },
+                    Some(TaskStatus::TaskCancelled) => {
+                        lbl_stack_progress.set_label("");
+                        Some(format!("{} Cancelled During: {}", name, stage))
                     },
                     None => {
+                        Some("Ready".to_owned())
+                    }
+                };
+
+                if let Some(status) = finished_status {
+                    if let Some(button) = monitor.tasks.remove(&proc_status.task_id).and_then(|t| t.button) {
+                        button.set_sensitive(true);
+                    }
+                    if shown {
+                        // Moves on to the latest task still running
+                        monitor.shown = monitor.tasks.keys().max().copied();
+                        if let Some(task) = monitor.shown.and_then(|id| monitor.tasks.get(&id)) {
+                            progress.pulse();
+                            label.set_label(&task.label());
+                        }
+                    }
+                    if monitor.tasks.is_empty() {
                         // label.set_visible(false);
                         progress.set_visible(false);
                         cancel.set_visible(false);
-                        start.set_sensitive(true);
                         cancel.set_sensitive(false);
-                        btn_thresh_test.set_sensitive(true);
-                        btn_analysis.set_sensitive(true);
-                        label.set_label("Ready");
+                        label.set_label(&status);
                     }
-                };
-
+                }
 
                 Continue(true)
             }
@@ -704,43 +1595,584 @@ fn build_ui(application: &Application) {
     // If there's a file in the parameters state already (such as from saved state),
     // we need to update the preview pane.
     
-    if let Some(light_path) = get_state_param!(light) {
-        update_preview_from_ser_file!(builder, light_path, "img_preview_light");
+    let first_light = get_state_param!(lights).first().cloned();
+    if let Some(light_path) = first_light {
+        update_preview_from_ser_file!(light_path, "preview_light");
     }
     if let Some(dark_path) = get_state_param!(dark) {
-        update_preview_from_ser_file!(builder, dark_path, "img_preview_dark");
+        update_preview_from_ser_file!(dark_path, "preview_dark");
     }
     if let Some(flat_path) = get_state_param!(flat) {
-        update_preview_from_ser_file!(builder, flat_path, "img_preview_flat");
+        update_preview_from_ser_file!(flat_path, "preview_flat");
     }
     if let Some(darkflat_path) = get_state_param!(darkflat) {
-        update_preview_from_ser_file!(builder, darkflat_path, "img_preview_darkflat");
+        update_preview_from_ser_file!(darkflat_path, "preview_darkflat");
     }
     if let Some(bias_path) = get_state_param!(bias) {
-        update_preview_from_ser_file!(builder, bias_path, "img_preview_bias");
+        update_preview_from_ser_file!(bias_path, "preview_bias");
     }
 
     window.present();
 }
 
-fn open_ser_file<F>(title: &str, window: &ApplicationWindow, initial_file:Option<PathBuf>,callback: F)
+/// Applies the overwrite policy when the output file already exists, or will be
+/// written by a queued job, then calls `callback` with the filename to write. Nothing
+/// is called if the user cancels at the prompt.
+fn resolve_output_collision<F>(window: &ApplicationWindow, output_filename: PathBuf, reserved: Vec<PathBuf>, callback: F)
+where
+    F: FnOnce(PathBuf) + 'static,
+{
+    if !is_output_taken(&output_filename, &reserved) {
+        callback(output_filename);
+        return;
+    }
+
+    let overwrite_policy = get_state_param!(overwrite_policy);
+    match overwrite_policy {
+        OverwritePolicy::Overwrite => {
+            warn!("Overwriting existing output {:?}", output_filename);
+            callback(output_filename);
+        }
+        OverwritePolicy::Increment => {
+            callback(next_free_filename(&output_filename, &reserved));
+        }
+        OverwritePolicy::Prompt => {
+            let dialog = AlertDialog::builder()
+                .modal(true)
+                .message("Output Already Exists")
+                .detail(format!(
+                    "{} already exists or is queued to be written.",
+                    output_filename.file_name().unwrap().to_string_lossy()
+                ))
+                .cancel_button(0)
+                .default_button(0)
+                .build();
+            dialog.set_buttons(&["Cancel", "Add Number", "Overwrite"]);
+            dialog.choose(Some(window), gio::Cancellable::NONE, move |response| match response {
+                Ok(1) => callback(next_free_filename(&output_filename, &reserved)),
+                Ok(2) => callback(output_filename),
+                _ => info!("Processing cancelled to keep the existing output"),
+            });
+        }
+    }
+}
+
+/// Adds a task to the task monitor so it can be cancelled, and shows its progress.
+/// `button` is made insensitive until the task finishes.
+fn register_task(
+    running_tasks: &TaskRegistry,
+    task: &TaskContext,
+    name: &str,
+    button: Option<Button>,
+) {
+    if let Some(button) = &button {
+        button.set_sensitive(false);
+    }
+    let mut monitor = running_tasks.borrow_mut();
+    monitor.tasks.insert(
+        task.id,
+        RunningTask {
+            name: name.to_owned(),
+            cancel: task.cancel_token(),
+            stage: String::new(),
+            button,
+        },
+    );
+    monitor.shown = Some(task.id);
+}
+
+/// Creates a task with a snapshot of the current parameters and registers it
+fn start_task(
+    running_tasks: &TaskRegistry,
+    sender: &Sender<TaskStatusContainer>,
+    name: &str,
+    button: Option<Button>,
+) -> TaskContext {
+    let params = STATE.lock().unwrap().params.clone();
+    let task = TaskContext::new(params, sender.clone());
+    register_task(running_tasks, &task, name, button);
+    task
+}
+
+fn show_error_dialog(window: &ApplicationWindow, detail: &str) {
+    let info_dialog = AlertDialog::builder()
+        .modal(true)
+        .message("Error")
+        .detail(detail)
+        .build();
+
+    info_dialog.show(Some(window));
+}
+
+/// Repopulates the profile selector from the saved profiles
+#[allow(deprecated)]
+fn refresh_profile_list(builder: &Builder) {
+    let combo_profile: ComboBoxText = bind_object!(builder, "combo_profile");
+    let txt_profile_name: Entry = bind_object!(builder, "txt_profile_name");
+
+    let (names, active) = {
+        let state = STATE.lock().unwrap();
+        (state.profiles.names(), state.profiles.active.to_owned())
+    };
+
+    combo_profile.remove_all();
+    names.iter().for_each(|name| {
+        combo_profile.append(Some(name), name);
+    });
+    combo_profile.set_active_id(active.as_deref());
+    txt_profile_name.set_text(&active.unwrap_or_default());
+}
+
+/// Writes the profiles out as soon as they change, rather than only on exit
+fn save_profiles(window: &ApplicationWindow) {
+    let result = STATE.lock().unwrap().profiles.save_to_userhome();
+    if let Err(why) = result {
+        error!("Failed to save profiles: {:?}", why);
+        show_error_dialog(window, &why.to_string());
+    }
+}
+
+/// Sets the profile values into their controls. The controls' change handlers take
+/// care of updating the parameters state.
+#[allow(deprecated)]
+fn apply_profile_to_ui(builder: &Builder, profile: &ProcessingProfile) {
+    let set_spinner = |obj_id: &str, value: f64| {
+        let spn_obj: SpinButton = bind_object!(builder, obj_id);
+        spn_obj.set_value(value);
+    };
+
+    set_spinner(
+        "spn_obj_detection_threshold",
+        profile.obj_detection_threshold,
+    );
+    set_spinner("spn_max_frames", profile.max_frames as f64);
+    set_spinner("spn_min_sigma", profile.min_sigma);
+    set_spinner("spn_max_sigma", profile.max_sigma);
+    set_spinner("spn_top_percentage", profile.top_percentage);
+    set_spinner("spn_window_size", profile.analysis_window_size as f64);
+
+    let combo_quality_metric: ComboBoxText = bind_object!(builder, "combo_quality_metric");
+    combo_quality_metric.set_active_id(Some(profile.quality_metric.id()));
+    set_spinner("spn_ldcorrect_coefficient", profile.ld_coefficient);
+    set_spinner("spn_solar_radius", profile.solar_radius_pixels as f64);
+
+    let combo_drizzle: ComboBoxText = bind_object!(builder, "combo_drizzle");
+    match profile.drizzle_scale {
+        Scale::Scale1_0 => combo_drizzle.set_active_id(Some("0")),
+        Scale::Scale1_5 => combo_drizzle.set_active_id(Some("1")),
+        Scale::Scale2_0 => combo_drizzle.set_active_id(Some("2")),
+        Scale::Scale3_0 => combo_drizzle.set_active_id(Some("3")),
+    };
+
+    let chk_ldcorrection: CheckButton = bind_object!(builder, "chk_ldcorrection");
+    chk_ldcorrection.set_active(profile.ld_correction);
+}
+
+/// Updates every control from the parameters state, such as after the state has
+/// been replaced by settings loaded from a previous run.
+#[allow(deprecated)]
+fn refresh_ui_from_state(builder: &Builder) {
+    let params = STATE.lock().unwrap().params.clone();
+
+    [
+        ("lbl_dark", &params.dark),
+        ("lbl_flat", &params.flat),
+        ("lbl_darkflat", &params.darkflat),
+        ("lbl_bias", &params.bias),
+        ("lbl_hotpixelmap", &params.hot_pixel_map),
+    ]
+    .iter()
+    .for_each(|(label_id, path)| {
+        let label: Label = bind_object!(builder, *label_id);
+        if let Some(path) = path {
+            label.set_label(path.file_name().unwrap().to_str().unwrap());
+        } else {
+            label.set_label("");
+        }
+    });
+
+    let lbl_output_folder: Label = bind_object!(builder, "lbl_output_folder");
+    if let Some(output_dir) = &params.output_dir {
+        lbl_output_folder.set_label(output_dir.to_str().unwrap());
+    } else {
+        lbl_output_folder.set_label("");
+    }
+
+    let txt_freetext: Entry = bind_object!(builder, "txt_freetext");
+    txt_freetext.set_text(&params.freetext);
+
+    let combo_target: ComboBoxText = bind_object!(builder, "combo_target");
+    match params.target {
+        Target::Sun => combo_target.set_active_id(Some("0")),
+        Target::Moon => combo_target.set_active_id(Some("1")),
+        Target::None => combo_target.set_active_id(Some("2")),
+    };
+
+    let spn_obs_latitude: SpinButton = bind_object!(builder, "spn_obs_latitude");
+    spn_obs_latitude.set_value(params.obs_latitude);
+    let spn_obs_longitude: SpinButton = bind_object!(builder, "spn_obs_longitude");
+    spn_obs_longitude.set_value(params.obs_longitude);
+
+    let chk_decorr_colors: CheckButton = bind_object!(builder, "chk_decorrelated_color");
+    chk_decorr_colors.set_active(params.decorrelated_colors);
+
+    [
+        ("combo_dark_method", params.dark_method),
+        ("combo_flat_method", params.flat_method),
+        ("combo_darkflat_method", params.darkflat_method),
+        ("combo_bias_method", params.bias_method),
+    ]
+    .into_iter()
+    .for_each(|(combo_id, method)| {
+        let combo: ComboBoxText = bind_object!(builder, combo_id);
+        combo.set_active_id(Some(method.id()));
+    });
+
+    let chk_save_masters: CheckButton = bind_object!(builder, "chk_save_masters");
+    chk_save_masters.set_active(params.save_masters);
+    let txt_output_pattern: Entry = bind_object!(builder, "txt_output_pattern");
+    txt_output_pattern.set_text(&params.output_pattern);
+    let combo_output_format: ComboBoxText = bind_object!(builder, "combo_output_format");
+    combo_output_format.set_active_id(Some(params.output_format.id()));
+    let combo_overwrite_policy: ComboBoxText = bind_object!(builder, "combo_overwrite_policy");
+    combo_overwrite_policy.set_active_id(Some(params.overwrite_policy.id()));
+
+    let combo_master_format: ComboBoxText = bind_object!(builder, "combo_master_format");
+    match params.master_format {
+        MasterFormat::Fits => combo_master_format.set_active_id(Some("Fits")),
+        MasterFormat::Tiff => combo_master_format.set_active_id(Some("Tiff")),
+    };
+
+    let combo_quality_metric: ComboBoxText = bind_object!(builder, "combo_quality_metric");
+    combo_quality_metric.set_active_id(Some(params.quality_metric.id()));
+    let combo_region_combine: ComboBoxText = bind_object!(builder, "combo_region_combine");
+    combo_region_combine.set_active_id(Some(params.region_combine.id()));
+    viewer::set_analysis_windows("preview_light", &params);
+
+    refresh_deconvolution_controls(builder, &params.deconvolution);
+    refresh_sharpen_controls(builder, &params.sharpening);
+
+    // The remaining processing values are the same ones kept in profiles
+    apply_profile_to_ui(builder, &ProcessingProfile::from_parameters("", &params));
+
+    on_lights_changed(builder);
+    [
+        (params.dark, "preview_dark"),
+        (params.flat, "preview_flat"),
+        (params.darkflat, "preview_darkflat"),
+        (params.bias, "preview_bias"),
+    ]
+    .into_iter()
+    .for_each(|(path, preview_id)| {
+        if let Some(path) = path {
+            update_preview_from_ser_file!(path, preview_id);
+        }
+    });
+}
+
+/// Sets the deconvolution controls from `deconvolution`. The Moffat beta is only
+/// enabled for the Moffat PSF.
+#[allow(deprecated)]
+fn refresh_deconvolution_controls(builder: &Builder, deconvolution: &DeconvolutionParameters) {
+    let chk_deconvolution_enabled: CheckButton = bind_object!(builder, "chk_deconvolution_enabled");
+    chk_deconvolution_enabled.set_active(deconvolution.enabled);
+
+    let combo_deconvolution_psf: ComboBoxText = bind_object!(builder, "combo_deconvolution_psf");
+    match deconvolution.psf {
+        PsfShape::Gaussian => combo_deconvolution_psf.set_active_id(Some("Gaussian")),
+        PsfShape::Moffat => combo_deconvolution_psf.set_active_id(Some("Moffat")),
+    };
+    combo_deconvolution_psf.set_sensitive(deconvolution.enabled);
+
+    let spn_deconvolution_fwhm: SpinButton = bind_object!(builder, "spn_deconvolution_fwhm");
+    spn_deconvolution_fwhm.set_value(deconvolution.fwhm);
+    spn_deconvolution_fwhm.set_sensitive(deconvolution.enabled);
+
+    let spn_deconvolution_beta: SpinButton = bind_object!(builder, "spn_deconvolution_beta");
+    spn_deconvolution_beta.set_value(deconvolution.moffat_beta);
+    spn_deconvolution_beta.set_sensitive(deconvolution.enabled && deconvolution.psf == PsfShape::Moffat);
+
+    let spn_deconvolution_iterations: SpinButton = bind_object!(builder, "spn_deconvolution_iterations");
+    spn_deconvolution_iterations.set_value(deconvolution.iterations as f64);
+    spn_deconvolution_iterations.set_sensitive(deconvolution.enabled);
+}
+
+/// Sets the sharpening controls from `sharpening` and enables only those used by the
+/// selected method
+#[allow(deprecated)]
+fn refresh_sharpen_controls(builder: &Builder, sharpening: &SharpenParameters) {
+    let chk_sharpen_enabled: CheckButton = bind_object!(builder, "chk_sharpen_enabled");
+    chk_sharpen_enabled.set_active(sharpening.enabled);
+
+    let combo_sharpen_method: ComboBoxText = bind_object!(builder, "combo_sharpen_method");
+    match sharpening.method {
+        SharpenMethod::Wavelets => combo_sharpen_method.set_active_id(Some("Wavelets")),
+        SharpenMethod::UnsharpMask => combo_sharpen_method.set_active_id(Some("UnsharpMask")),
+    };
+
+    sharpening.layers.iter().take(sharpen::WAVELET_LAYERS).enumerate().for_each(|(n, layer)| {
+        let spn_gain: SpinButton = bind_object!(builder, &format!("spn_wavelet_gain_{}", n));
+        spn_gain.set_value(layer.gain);
+        let spn_denoise: SpinButton = bind_object!(builder, &format!("spn_wavelet_denoise_{}", n));
+        spn_denoise.set_value(layer.denoise);
+    });
+
+    let spn_unsharp_radius: SpinButton = bind_object!(builder, "spn_unsharp_radius");
+    spn_unsharp_radius.set_value(sharpening.unsharp_radius);
+    let spn_unsharp_amount: SpinButton = bind_object!(builder, "spn_unsharp_amount");
+    spn_unsharp_amount.set_value(sharpening.unsharp_amount);
+
+    let grid_wavelet_layers: gtk::Grid = bind_object!(builder, "grid_wavelet_layers");
+    grid_wavelet_layers.set_sensitive(sharpening.method == SharpenMethod::Wavelets);
+    let grid_unsharp_mask: gtk::Grid = bind_object!(builder, "grid_unsharp_mask");
+    grid_unsharp_mask.set_sensitive(sharpening.method == SharpenMethod::UnsharpMask);
+}
+
+/// Rebuilds the list of light inputs with the number of frames in each file
+fn refresh_light_list(builder: &Builder) {
+    let lst_light: ListBox = bind_object!(builder, "lst_light");
+    while let Some(row) = lst_light.row_at_index(0) {
+        lst_light.remove(&row);
+    }
+
+    let lights = get_state_param!(lights);
+    let num_lights = lights.len();
+    lights.iter().enumerate().for_each(|(i, light)| {
+        let frame_count = match SerFile::load_ser(light.to_str().unwrap()) {
+            Ok(ser_file) => format!("{} frames", ser_file.frame_count),
+            Err(why) => {
+                error!("Failed to read ser file {:?}: {:?}", light, why);
+                "Invalid".to_owned()
+            }
+        };
+
+        let row = gtk::Box::new(Orientation::Horizontal, 2);
+        row.append(
+            &Label::builder()
+                .label(light.file_name().unwrap().to_str().unwrap())
+                .tooltip_text(light.to_str().unwrap())
+                .ellipsize(EllipsizeMode::Middle)
+                .hexpand(true)
+                .xalign(0.0)
+                .build(),
+        );
+        row.append(&Label::new(Some(&frame_count)));
+
+        let btn_up = Button::from_icon_name("go-up");
+        btn_up.set_sensitive(i > 0);
+        let b = builder.clone();
+        btn_up.connect_clicked(glib::clone!(@weak b as builder => move |_| {
+            STATE.lock().unwrap().params.lights.swap(i - 1, i);
+            on_lights_changed(&builder);
+        }));
+        row.append(&btn_up);
+
+        let btn_down = Button::from_icon_name("go-down");
+        btn_down.set_sensitive(i + 1 < num_lights);
+        let b = builder.clone();
+        btn_down.connect_clicked(glib::clone!(@weak b as builder => move |_| {
+            STATE.lock().unwrap().params.lights.swap(i, i + 1);
+            on_lights_changed(&builder);
+        }));
+        row.append(&btn_down);
+
+        let btn_remove = Button::from_icon_name("list-remove");
+        let b = builder.clone();
+        btn_remove.connect_clicked(glib::clone!(@weak b as builder => move |_| {
+            STATE.lock().unwrap().params.lights.remove(i);
+            on_lights_changed(&builder);
+        }));
+        row.append(&btn_remove);
+
+        lst_light.append(&row);
+    });
+}
+
+/// Updates everything that depends on the light inputs after they've been added,
+/// removed, or reordered.
+fn on_lights_changed(builder: &Builder) {
+    STATE.lock().unwrap().params.drop_stale_exclusions();
+    refresh_light_list(builder);
+    update_output_filename!(builder);
+    update_execute_state!(builder);
+    let first_light = get_state_param!(lights).first().cloned();
+    if let Some(light_path) = first_light {
+        update_preview_from_ser_file!(light_path, "preview_light");
+    }
+}
+
+/// Rebuilds the job queue list from the current state of the queue
+fn refresh_queue_list(builder: &Builder) {
+    let lst_queue: ListBox = bind_object!(builder, "lst_queue");
+    while let Some(row) = lst_queue.row_at_index(0) {
+        lst_queue.remove(&row);
+    }
+
+    let (jobs, running) = {
+        let queue = QUEUE.lock().unwrap();
+        (queue.jobs.clone(), queue.running)
+    };
+
+    let btn_queue_run: Button = bind_object!(builder, "btn_queue_run");
+    btn_queue_run.set_sensitive(!running && jobs.iter().any(|j| j.status == JobStatus::Pending));
+
+    let lbl_queue_status: Label = bind_object!(builder, "lbl_queue_status");
+    lbl_queue_status.set_label(&format!(
+        "{} jobs, {} pending{}",
+        jobs.len(),
+        jobs.iter().filter(|j| j.status == JobStatus::Pending).count(),
+        if running { ", running" } else { "" }
+    ));
+
+    let num_jobs = jobs.len();
+    jobs.iter().enumerate().for_each(|(i, job)| {
+        let id = job.id;
+        let row = gtk::Box::new(Orientation::Horizontal, 2);
+        row.append(
+            &Label::builder()
+                .label(job.output_filename.file_name().unwrap().to_str().unwrap())
+                .tooltip_text(job.output_filename.to_str().unwrap())
+                .ellipsize(EllipsizeMode::Middle)
+                .hexpand(true)
+                .xalign(0.0)
+                .build(),
+        );
+
+        let status_label = match &job.status {
+            JobStatus::Pending => "Pending".to_owned(),
+            JobStatus::Running => "Running".to_owned(),
+            JobStatus::Completed => "Completed".to_owned(),
+            JobStatus::Failed(why) => format!("Failed: {}", why),
+            JobStatus::Cancelled => "Cancelled".to_owned(),
+        };
+        row.append(
+            &Label::builder()
+                .label(&status_label)
+                .tooltip_text(&status_label)
+                .ellipsize(EllipsizeMode::End)
+                .max_width_chars(30)
+                .build(),
+        );
+
+        let btn_retry = Button::from_icon_name("view-refresh");
+        btn_retry.set_tooltip_text(Some("Retry"));
+        btn_retry.set_sensitive(job.status.is_retryable());
+        let b = builder.clone();
+        btn_retry.connect_clicked(glib::clone!(@weak b as builder => move |_| {
+            QUEUE.lock().unwrap().retry(id);
+            refresh_queue_list(&builder);
+        }));
+        row.append(&btn_retry);
+
+        let btn_up = Button::from_icon_name("go-up");
+        btn_up.set_sensitive(i > 0);
+        let b = builder.clone();
+        btn_up.connect_clicked(glib::clone!(@weak b as builder => move |_| {
+            QUEUE.lock().unwrap().move_up(id);
+            refresh_queue_list(&builder);
+        }));
+        row.append(&btn_up);
+
+        let btn_down = Button::from_icon_name("go-down");
+        btn_down.set_sensitive(i + 1 < num_jobs);
+        let b = builder.clone();
+        btn_down.connect_clicked(glib::clone!(@weak b as builder => move |_| {
+            QUEUE.lock().unwrap().move_down(id);
+            refresh_queue_list(&builder);
+        }));
+        row.append(&btn_down);
+
+        let btn_remove = Button::from_icon_name("list-remove");
+        btn_remove.set_sensitive(job.status != JobStatus::Running);
+        let b = builder.clone();
+        btn_remove.connect_clicked(glib::clone!(@weak b as builder => move |_| {
+            QUEUE.lock().unwrap().remove(id);
+            refresh_queue_list(&builder);
+        }));
+        row.append(&btn_remove);
+
+        lst_queue.append(&row);
+    });
+}
+
+fn open_calibration_file<F>(title: &str, window: &ApplicationWindow, initial_file:Option<PathBuf>,callback: F)
 where
     F: Fn(PathBuf) + 'static,
 {
-    open_file(title, window, "*.ser", "SER", initial_file, callback);
+    open_file(
+        title,
+        window,
+        &["*.ser", "*.fits", "*.fit", "*.fts", "*.tif", "*.tiff"],
+        "SER or Master Frames",
+        initial_file,
+        callback,
+    );
+}
+
+fn open_ser_files<F>(
+    title: &str,
+    window: &ApplicationWindow,
+    initial_file: Option<PathBuf>,
+    callback: F,
+) where
+    F: Fn(Vec<PathBuf>) + 'static,
+{
+    let initial_file = if let Some(f) = initial_file {
+        gtk::gio::File::for_path(f)
+    } else {
+        gtk::gio::File::for_path(dirs::home_dir().unwrap())
+    };
+
+    let filters = gio::ListStore::new(Type::OBJECT);
+    let ser_filter = gtk::FileFilter::new();
+    ser_filter.set_name(Some("SER"));
+    ser_filter.add_pattern("*.ser");
+    filters.append(&ser_filter);
+
+    let dialog = gtk::FileDialog::builder()
+        .title(title)
+        .accept_label("Open")
+        .modal(true)
+        .filters(&filters)
+        .initial_file(&initial_file)
+        .build();
+
+    dialog.open_multiple(Some(window), gio::Cancellable::NONE, move |files| {
+        if let Ok(files) = files {
+            let filenames: Vec<PathBuf> = (0..files.n_items())
+                .filter_map(|i| files.item(i).and_downcast::<gio::File>())
+                .filter_map(|f| f.path())
+                .collect();
+            callback(filenames);
+        }
+    });
 }
 
 fn open_toml_file<F>(title: &str, window: &ApplicationWindow, initial_file:Option<PathBuf>,callback: F)
 where
     F: Fn(PathBuf) + 'static,
 {
-    open_file(title, window, "*.toml", "toml", initial_file, callback);
+    open_file(title, window, &["*.toml"], "toml", initial_file, callback);
+}
+
+fn open_output_file<F>(title: &str, window: &ApplicationWindow, initial_file:Option<PathBuf>,callback: F)
+where
+    F: Fn(PathBuf) + 'static,
+{
+    open_file(
+        title,
+        window,
+        &["*.tif", "*.tiff", "*.fits", "*.png", "*.jpg", "*.json"],
+        "SolHat Outputs",
+        initial_file,
+        callback,
+    );
 }
 
 fn open_file<F>(
     title: &str,
     window: &ApplicationWindow,
-    filter: &str,
+    patterns: &[&str],
     mimename: &str,
     initial_file:Option<PathBuf>,
     callback: F,
@@ -758,7 +2190,7 @@ fn open_file<F>(
     let ser_filter = gtk::FileFilter::new();
     // ser_filter.add_mime_type(mimetype);
     ser_filter.set_name(Some(mimename));
-    ser_filter.add_pattern(filter);
+    patterns.iter().for_each(|p| ser_filter.add_pattern(p));
     filters.append(&ser_filter);
 
     let dialog = gtk::FileDialog::builder()
@@ -806,47 +2238,17 @@ where
     });
 }
 
-fn assemble_output_filename() -> Result<PathBuf> {
-    let state = STATE.lock().unwrap();
-
-    let output_dir = if let Some(output_dir) = &state.params.output_dir {
+fn assemble_output_filename(params: &ParametersState) -> Result<PathBuf> {
+    let output_dir = if let Some(output_dir) = &params.output_dir {
         output_dir.to_owned()
     } else {
         dirs::home_dir().unwrap()
     };
 
-    let base_filename = if let Some(input_file) = &state.params.light {
-        Path::new(input_file.file_name().unwrap())
-            .file_stem()
-            .unwrap()
-    } else {
-        OsStr::new("Unknown")
-    };
-
-    let freetext = if !state.params.freetext.is_empty() {
-        format!("_{}", state.params.freetext)
-    } else {
-        "".to_owned()
-    };
-
-    let drizzle = match state.params.drizzle_scale {
-        Scale::Scale1_0 => "".to_owned(),
-        _ => format!(
-            "_{}",
-            state
-                .params
-                .drizzle_scale
-                .to_string()
-                .replace([' ', '.'], "")
-        ),
-    };
-
     let output_filename = format!(
-        "{}_{:?}{}{}.tif",
-        base_filename.to_string_lossy().as_ref(),
-        state.params.target,
-        drizzle,
-        freetext
+        "{}.{}",
+        expand_pattern(&params.output_pattern, &PatternValues::from_parameters(params))?,
+        params.output_format.extension()
     );
     let output_path: PathBuf = Path::new(&output_dir).join(output_filename);
     Ok(output_path)
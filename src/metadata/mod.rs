@@ -0,0 +1,217 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use solhat::context::{ProcessContext, ProcessParameters};
+use solhat::drizzle::Scale;
+use solhat::target::Target;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::state::ParametersState;
+
+///////////////////////////////////////////////////////
+/// Run Metadata Sidecar
+///////////////////////////////////////////////////////
+
+/// Serializable copy of the SolHat `ProcessParameters` used for a run
+#[derive(Deserialize, Serialize, Clone)]
+pub struct RunParameters {
+    pub input_files: Vec<String>,
+    pub obj_detection_threshold: f64,
+    pub obs_latitude: f64,
+    pub obs_longitude: f64,
+    pub target: Target,
+    pub crop_width: Option<usize>,
+    pub crop_height: Option<usize>,
+    pub max_frames: Option<usize>,
+    pub min_sigma: Option<f64>,
+    pub max_sigma: Option<f64>,
+    pub top_percentage: Option<f64>,
+    pub drizzle_scale: Scale,
+    pub initial_rotation: f64,
+    pub flat_inputs: Option<String>,
+    pub dark_inputs: Option<String>,
+    pub darkflat_inputs: Option<String>,
+    pub bias_inputs: Option<String>,
+    pub hot_pixel_map: Option<String>,
+    pub analysis_window_size: usize,
+    pub vert_offset: i32,
+    pub horiz_offset: i32,
+}
+
+impl From<&ProcessParameters> for RunParameters {
+    fn from(params: &ProcessParameters) -> Self {
+        Self {
+            input_files: params.input_files.clone(),
+            obj_detection_threshold: params.obj_detection_threshold,
+            obs_latitude: params.obs_latitude,
+            obs_longitude: params.obs_longitude,
+            target: params.target,
+            crop_width: params.crop_width,
+            crop_height: params.crop_height,
+            max_frames: params.max_frames,
+            min_sigma: params.min_sigma,
+            max_sigma: params.max_sigma,
+            top_percentage: params.top_percentage,
+            drizzle_scale: params.drizzle_scale,
+            initial_rotation: params.initial_rotation,
+            flat_inputs: params.flat_inputs.clone(),
+            dark_inputs: params.dark_inputs.clone(),
+            darkflat_inputs: params.darkflat_inputs.clone(),
+            bias_inputs: params.bias_inputs.clone(),
+            hot_pixel_map: params.hot_pixel_map.clone(),
+            analysis_window_size: params.analysis_window_size,
+            vert_offset: params.vert_offset,
+            horiz_offset: params.horiz_offset,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct CalibrationFiles {
+    pub flat: Option<String>,
+    pub dark: Option<String>,
+    pub darkflat: Option<String>,
+    pub bias: Option<String>,
+    pub hot_pixel_map: Option<String>,
+}
+
+/// A frame which made it into the stack
+#[derive(Deserialize, Serialize, Clone)]
+pub struct FrameMetadata {
+    pub source_file: String,
+    pub frame_id: usize,
+    pub sigma: f64,
+    /// Parallactic rotation applied to the frame, in radians
+    pub computed_rotation: f64,
+    pub offset_h: f64,
+    pub offset_v: f64,
+}
+
+/// Describes how a stacked output was made. Written as a JSON file beside the output.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct RunMetadata {
+    pub software: String,
+    pub version: String,
+    pub created: String,
+    pub output_file: String,
+    pub parameters: RunParameters,
+    pub calibration: CalibrationFiles,
+    pub stack_min: f32,
+    pub stack_max: f32,
+    pub frame_count: usize,
+    pub frames: Vec<FrameMetadata>,
+
+    /// The full UI parameters state used for the run. Used to reproduce a run, and
+    /// not present in sidecars from older versions.
+    #[serde(default)]
+    pub settings: Option<ParametersState>,
+}
+
+impl RunMetadata {
+    pub fn new(
+        context: &ProcessContext,
+        params_state: &ParametersState,
+        output_filename: &Path,
+        stack_min: f32,
+        stack_max: f32,
+    ) -> Self {
+        let params = &context.parameters;
+        Self {
+            software: env!("CARGO_PKG_NAME").to_owned(),
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            created: chrono::Utc::now().to_rfc3339(),
+            output_file: output_filename.to_string_lossy().to_string(),
+            parameters: RunParameters::from(params),
+            calibration: CalibrationFiles {
+                flat: params.flat_inputs.clone(),
+                dark: params.dark_inputs.clone(),
+                darkflat: params.darkflat_inputs.clone(),
+                bias: params.bias_inputs.clone(),
+                hot_pixel_map: params.hot_pixel_map.clone(),
+            },
+            stack_min,
+            stack_max,
+            frame_count: context.frame_records.len(),
+            frames: context
+                .frame_records
+                .iter()
+                .map(|fr| FrameMetadata {
+                    source_file: fr.source_file_id.to_string(),
+                    frame_id: fr.frame_id,
+                    sigma: fr.sigma,
+                    computed_rotation: fr.computed_rotation,
+                    offset_h: fr.offset.h as f64,
+                    offset_v: fr.offset.v as f64,
+                })
+                .collect(),
+            settings: Some(params_state.clone()),
+        }
+    }
+
+    /// Loads the metadata for an output file. `file_path` may be the output itself
+    /// or its JSON sidecar.
+    pub fn load_sidecar(file_path: &Path) -> Result<Self> {
+        let sidecar_path = if file_path.extension().map(|e| e == "json") == Some(true) {
+            file_path.to_owned()
+        } else {
+            RunMetadata::sidecar_path(file_path)
+        };
+
+        if !sidecar_path.exists() {
+            return Err(anyhow!("No run metadata found at {:?}", sidecar_path));
+        }
+        info!("Loading run metadata from {:?}", sidecar_path);
+        let t = std::fs::read_to_string(sidecar_path)?;
+        Ok(serde_json::from_str(&t)?)
+    }
+
+    /// The parameters needed to repeat this run. Sidecars without the full settings
+    /// fall back to what can be recovered from the SolHat parameters.
+    pub fn to_parameters_state(&self) -> ParametersState {
+        if let Some(settings) = &self.settings {
+            return settings.clone();
+        }
+
+        let p = &self.parameters;
+        let defaults = ParametersState::default();
+        ParametersState {
+            lights: p.input_files.iter().map(PathBuf::from).collect(),
+            dark: p.dark_inputs.as_ref().map(PathBuf::from),
+            flat: p.flat_inputs.as_ref().map(PathBuf::from),
+            darkflat: p.darkflat_inputs.as_ref().map(PathBuf::from),
+            bias: p.bias_inputs.as_ref().map(PathBuf::from),
+            hot_pixel_map: p.hot_pixel_map.as_ref().map(PathBuf::from),
+            output_dir: Path::new(&self.output_file).parent().map(|d| d.to_owned()),
+            obs_latitude: p.obs_latitude,
+            obs_longitude: p.obs_longitude,
+            target: p.target,
+            obj_detection_threshold: p.obj_detection_threshold,
+            drizzle_scale: p.drizzle_scale,
+            max_frames: p.max_frames.unwrap_or(defaults.max_frames),
+            min_sigma: p.min_sigma.unwrap_or(defaults.min_sigma),
+            max_sigma: p.max_sigma.unwrap_or(defaults.max_sigma),
+            top_percentage: p.top_percentage.unwrap_or(defaults.top_percentage),
+            analysis_window_size: p.analysis_window_size,
+            vert_offset: p.vert_offset,
+            horiz_offset: p.horiz_offset,
+            ..defaults
+        }
+    }
+
+    /// The sidecar path for an output file, e.g. `sun_Sun.tif` -> `sun_Sun.tif.json`
+    pub fn sidecar_path(output_filename: &Path) -> PathBuf {
+        let mut sidecar = output_filename.as_os_str().to_owned();
+        sidecar.push(".json");
+        PathBuf::from(sidecar)
+    }
+
+    pub fn save_sidecar(&self, output_filename: &Path) -> Result<()> {
+        let sidecar_path = RunMetadata::sidecar_path(output_filename);
+        info!("Writing run metadata to {:?}", sidecar_path);
+        let json_str = serde_json::to_string_pretty(&self)?;
+        let mut f = File::create(sidecar_path)?;
+        f.write_all(json_str.as_bytes())?;
+        Ok(())
+    }
+}
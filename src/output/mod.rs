@@ -0,0 +1,232 @@
+use anyhow::Result;
+use sciimg::prelude::*;
+use serde::{Deserialize, Serialize};
+use solhat::ser::SerFile;
+use std::path::{Path, PathBuf};
+
+use crate::fits::{HeaderCard, HeaderValue};
+use crate::state::ParametersState;
+
+pub mod pattern;
+
+///////////////////////////////////////////////////////
+/// Stacked Output Formats
+///////////////////////////////////////////////////////
+
+/// File format of the stacked output
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    /// Normalized 16-bit TIFF
+    #[default]
+    Tiff,
+    /// 32-bit floating point FITS of the stack before normalization
+    Fits,
+    /// Normalized 16-bit PNG
+    Png,
+    /// Normalized 8-bit JPEG, for sharing
+    Jpeg,
+}
+
+impl OutputFormat {
+    pub fn extension(&self) -> &str {
+        match self {
+            OutputFormat::Tiff => "tif",
+            OutputFormat::Fits => "fits",
+            OutputFormat::Png => "png",
+            OutputFormat::Jpeg => "jpg",
+        }
+    }
+
+    /// Identifier used by the output format selector
+    pub fn id(&self) -> &str {
+        match self {
+            OutputFormat::Tiff => "Tiff",
+            OutputFormat::Fits => "Fits",
+            OutputFormat::Png => "Png",
+            OutputFormat::Jpeg => "Jpeg",
+        }
+    }
+
+    pub fn from_id(id: &str) -> Self {
+        match id {
+            "Fits" => OutputFormat::Fits,
+            "Png" => OutputFormat::Png,
+            "Jpeg" => OutputFormat::Jpeg,
+            _ => OutputFormat::Tiff,
+        }
+    }
+
+    /// The format matching the extension of `path`, if it's one we can write
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .as_deref()
+        {
+            Some("tif") | Some("tiff") => Some(OutputFormat::Tiff),
+            Some("fits") | Some("fit") | Some("fts") => Some(OutputFormat::Fits),
+            Some("png") => Some(OutputFormat::Png),
+            Some("jpg") | Some("jpeg") => Some(OutputFormat::Jpeg),
+            _ => None,
+        }
+    }
+
+    /// Whether the output is written from the stack before normalization
+    pub fn is_unnormalized(&self) -> bool {
+        *self == OutputFormat::Fits
+    }
+}
+
+/// What to do when the output file already exists
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum OverwritePolicy {
+    Overwrite,
+    /// Adds a number to the filename, e.g. `sun_Sun_1.tif`
+    Increment,
+    /// Asks before processing starts
+    #[default]
+    Prompt,
+}
+
+impl OverwritePolicy {
+    /// Identifier used by the overwrite policy selector
+    pub fn id(&self) -> &str {
+        match self {
+            OverwritePolicy::Overwrite => "Overwrite",
+            OverwritePolicy::Increment => "Increment",
+            OverwritePolicy::Prompt => "Prompt",
+        }
+    }
+
+    pub fn from_id(id: &str) -> Self {
+        match id {
+            "Overwrite" => OverwritePolicy::Overwrite,
+            "Increment" => OverwritePolicy::Increment,
+            _ => OverwritePolicy::Prompt,
+        }
+    }
+}
+
+/// Whether the output file exists or is already going to be written by another job
+pub fn is_output_taken(file_path: &Path, reserved: &[PathBuf]) -> bool {
+    file_path.exists() || reserved.iter().any(|r| r == file_path)
+}
+
+/// The first of `<stem>_1.<ext>`, `<stem>_2.<ext>`, ... that isn't taken
+pub fn next_free_filename(file_path: &Path, reserved: &[PathBuf]) -> PathBuf {
+    let stem = file_path
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let extension = file_path
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+    (1..)
+        .map(|n| file_path.with_file_name(format!("{}_{}{}", stem, n, extension)))
+        .find(|p| !is_output_taken(p, reserved))
+        .unwrap()
+}
+
+/// Saves a normalized 16-bit stack in one of the image formats. JPEG only supports
+/// 8 bits per channel so the stack is reduced for it.
+pub fn save_normalized(image: &Image, format: OutputFormat, file_path: &Path) -> Result<()> {
+    match format {
+        OutputFormat::Jpeg => {
+            let mut reduced = image.clone();
+            reduced.normalize_to_8bit();
+            reduced.save(file_path.to_string_lossy().as_ref())?;
+        }
+        OutputFormat::Fits => {
+            return Err(anyhow!(
+                "FITS output is written from the unnormalized stack"
+            ));
+        }
+        _ => image.save(file_path.to_string_lossy().as_ref())?,
+    }
+    Ok(())
+}
+
+/// Details of a capture read from the SER header and its first frame
+pub struct CaptureInfo {
+    pub camera: String,
+    /// Timestamp of the first frame, as an ISO 8601 UTC date and time
+    pub start: String,
+    pub date: String,
+    pub time_utc: String,
+}
+
+impl CaptureInfo {
+    pub fn from_ser_file(ser_file_path: &Path) -> Result<Self> {
+        let ser_file = SerFile::load_ser(ser_file_path.to_string_lossy().as_ref())?;
+        let timestamp = ser_file.get_frame(0)?.timestamp;
+        Ok(CaptureInfo {
+            camera: ser_file.instrument.trim().to_owned(),
+            start: timestamp.format("%Y-%m-%dT%H:%M:%S%.3f").to_string(),
+            date: timestamp.format("%Y-%m-%d").to_string(),
+            time_utc: timestamp.format("%H%M%S").to_string(),
+        })
+    }
+}
+
+/// Header keywords describing the observation, for FITS output
+pub fn fits_header(params: &ParametersState, frame_count: usize) -> Vec<HeaderCard> {
+    let mut cards = vec![
+        HeaderCard::new(
+            "OBJECT",
+            HeaderValue::Str(format!("{:?}", params.target)),
+            "observed target",
+        ),
+        HeaderCard::new(
+            "SITELAT",
+            HeaderValue::Float(params.obs_latitude),
+            "[deg] observer latitude",
+        ),
+        HeaderCard::new(
+            "SITELONG",
+            HeaderValue::Float(params.obs_longitude),
+            "[deg] observer longitude, east positive",
+        ),
+        HeaderCard::new(
+            "NCOMBINE",
+            HeaderValue::Int(frame_count as i64),
+            "number of frames stacked",
+        ),
+        HeaderCard::new(
+            "DRIZZLE",
+            HeaderValue::Str(params.drizzle_scale.to_string()),
+            "drizzle scale",
+        ),
+    ];
+
+    // The observation date is the timestamp of the first captured frame
+    if let Some(first_light) = params.lights.first() {
+        match CaptureInfo::from_ser_file(first_light) {
+            Ok(capture) => {
+                cards.push(HeaderCard::new(
+                    "DATE-OBS",
+                    HeaderValue::Str(capture.start),
+                    "[UTC] start of capture",
+                ));
+                if !capture.camera.is_empty() {
+                    cards.push(HeaderCard::new(
+                        "INSTRUME",
+                        HeaderValue::Str(capture.camera),
+                        "camera",
+                    ));
+                }
+            }
+            Err(why) => warn!(
+                "Unable to read capture date from {:?}: {:?}",
+                first_light, why
+            ),
+        }
+    }
+
+    cards.push(HeaderCard::new(
+        "CREATOR",
+        HeaderValue::Str(format!("SolHat-UI {}", env!("CARGO_PKG_VERSION"))),
+        "",
+    ));
+    cards
+}
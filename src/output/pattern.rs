@@ -0,0 +1,195 @@
+use anyhow::Result;
+use solhat::drizzle::Scale;
+use solhat::ser::SerFile;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use crate::output::CaptureInfo;
+use crate::state::ParametersState;
+
+///////////////////////////////////////////////////////
+/// Output Filename Patterns
+///////////////////////////////////////////////////////
+
+/// Matches the fixed filenames of earlier versions, e.g. `sun_001_Sun_15x_ha`
+pub const DEFAULT_PATTERN: &str = "{stem}_{target}_{drizzle}_{freetext}";
+
+/// Tokens that can be used in a pattern
+pub const TOKENS: [&str; 10] = [
+    "stem",
+    "target",
+    "drizzle",
+    "date",
+    "time_utc",
+    "frames",
+    "top_pct",
+    "threshold",
+    "camera",
+    "freetext",
+];
+
+/// Characters that separate tokens. A separator next to a token with no value is
+/// dropped so optional tokens don't leave doubled or trailing separators.
+const SEPARATORS: [char; 4] = ['_', '-', '.', ' '];
+
+/// Characters that can't be used in a filename on at least one platform
+const UNSAFE_CHARS: [char; 9] = ['/', '\\', ':', '*', '?', '"', '<', '>', '|'];
+
+/// Values read from the light inputs themselves
+#[derive(Clone, Default)]
+struct LightsInfo {
+    date: String,
+    time_utc: String,
+    camera: String,
+    frames: String,
+}
+
+impl LightsInfo {
+    fn from_lights(lights: &[PathBuf]) -> Self {
+        let mut info = LightsInfo::default();
+        if let Some(first_light) = lights.first() {
+            if let Ok(capture) = CaptureInfo::from_ser_file(first_light) {
+                info.date = capture.date;
+                info.time_utc = capture.time_utc;
+                info.camera = capture.camera;
+            }
+        }
+
+        let frames: usize = lights
+            .iter()
+            .filter_map(|f| SerFile::load_ser(f.to_string_lossy().as_ref()).ok())
+            .map(|s| s.frame_count)
+            .sum();
+        if frames > 0 {
+            info.frames = frames.to_string();
+        }
+        info
+    }
+}
+
+lazy_static! {
+    // The filename is assembled on every parameter change, so the light inputs are
+    // only read again when they change.
+    static ref LIGHTS_INFO: Mutex<Option<(Vec<PathBuf>, LightsInfo)>> = Mutex::new(None);
+}
+
+/// Values read from the light inputs, reusing the last ones if the inputs haven't changed
+fn lights_info(lights: &[PathBuf]) -> LightsInfo {
+    let mut cached = LIGHTS_INFO.lock().unwrap();
+    match &*cached {
+        Some((cached_lights, info)) if cached_lights.as_slice() == lights => info.clone(),
+        _ => {
+            let info = LightsInfo::from_lights(lights);
+            *cached = Some((lights.to_vec(), info.clone()));
+            info
+        }
+    }
+}
+
+/// Values substituted for the tokens of a pattern
+#[derive(Default)]
+pub struct PatternValues {
+    pub stem: String,
+    pub target: String,
+    pub drizzle: String,
+    pub date: String,
+    pub time_utc: String,
+    pub frames: String,
+    pub top_pct: String,
+    pub threshold: String,
+    pub camera: String,
+    pub freetext: String,
+}
+
+impl PatternValues {
+    /// Values for the current parameters. The date, time and camera are read from the
+    /// first light input and `frames` is the total number of frames in the light inputs,
+    /// as the number actually stacked isn't known until processing.
+    pub fn from_parameters(params: &ParametersState) -> Self {
+        let lights_info = lights_info(&params.lights);
+        PatternValues {
+            stem: params
+                .lights
+                .first()
+                .and_then(|f| f.file_stem())
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or_else(|| "Unknown".to_owned()),
+            target: format!("{:?}", params.target),
+            drizzle: match params.drizzle_scale {
+                Scale::Scale1_0 => "".to_owned(),
+                _ => params.drizzle_scale.to_string().replace([' ', '.'], ""),
+            },
+            top_pct: params.top_percentage.to_string(),
+            threshold: params.obj_detection_threshold.to_string(),
+            freetext: params.freetext.to_owned(),
+            date: lights_info.date,
+            time_utc: lights_info.time_utc,
+            camera: lights_info.camera,
+            frames: lights_info.frames,
+        }
+    }
+
+    fn get(&self, token: &str) -> Option<&str> {
+        match token {
+            "stem" => Some(&self.stem),
+            "target" => Some(&self.target),
+            "drizzle" => Some(&self.drizzle),
+            "date" => Some(&self.date),
+            "time_utc" => Some(&self.time_utc),
+            "frames" => Some(&self.frames),
+            "top_pct" => Some(&self.top_pct),
+            "threshold" => Some(&self.threshold),
+            "camera" => Some(&self.camera),
+            "freetext" => Some(&self.freetext),
+            _ => None,
+        }
+    }
+}
+
+/// Substitutes the values into a pattern such as `{date}_{target}_{camera}`. The
+/// result has no extension.
+pub fn expand_pattern(pattern: &str, values: &PatternValues) -> Result<String> {
+    let mut expanded = String::new();
+    let mut drop_separator = false;
+    let mut rest = pattern;
+
+    while !rest.is_empty() {
+        if let Some(token_start) = rest.strip_prefix('{') {
+            let end = token_start
+                .find('}')
+                .ok_or_else(|| anyhow!("Unclosed '{{' in pattern"))?;
+            let token = &token_start[..end];
+            let value = values
+                .get(token)
+                .ok_or_else(|| anyhow!("Unknown token {{{}}}", token))?
+                .replace(UNSAFE_CHARS, "-");
+            if value.is_empty() {
+                if expanded.ends_with(SEPARATORS) {
+                    expanded.pop();
+                } else {
+                    drop_separator = true;
+                }
+            } else {
+                expanded.push_str(&value);
+                drop_separator = false;
+            }
+            rest = &token_start[end + 1..];
+        } else {
+            let end = rest.find('{').unwrap_or(rest.len());
+            let mut literal = &rest[..end];
+            if drop_separator {
+                literal = literal.strip_prefix(SEPARATORS).unwrap_or(literal);
+                drop_separator = false;
+            }
+            expanded.push_str(&literal.replace(UNSAFE_CHARS, "-"));
+            rest = &rest[end..];
+        }
+    }
+
+    let expanded = expanded.trim_matches(&SEPARATORS[..]).to_owned();
+    if expanded.is_empty() {
+        Err(anyhow!("Pattern gives an empty filename"))
+    } else {
+        Ok(expanded)
+    }
+}
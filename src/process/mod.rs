@@ -5,40 +5,64 @@ use solhat::context::ProcessContext;
 use solhat::drizzle::BilinearDrizzle;
 use solhat::framerecord::FrameRecord;
 use solhat::ldcorrect;
-use solhat::limiting::frame_limit_determinate;
 // use solhat::offsetting::frame_offset_analysis;
+use rayon::prelude::*;
+use sciimg::prelude::*;
 use solhat::rotation::frame_rotation_analysis;
-use solhat::stacking::process_frame_stacking;
 use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::sync::Mutex;
 
-use crate::analysis::sigma::frame_analysis_window_size;
+use crate::analysis::sigma::{apply_frame_limits, frame_analysis_window_size};
 use crate::cancel::*;
+use crate::deconvolve;
+use crate::fits;
+use crate::metadata::RunMetadata;
+use crate::output;
+use crate::sharpen;
 use crate::state::*;
-use crate::taskstatus::*;
+use crate::task::TaskContext;
 
+/// Roughly how many live previews are sent while stacking
+const STACK_PREVIEW_COUNT: usize = 20;
+
+/// Frames handed to SolHat's rotation analysis at a time, so a cancel is noticed
+/// between batches
+const ROTATION_BATCH_FRAMES: usize = 64;
+
+/// A normalized copy of the partially stacked image
+pub struct StackSnapshot {
+    pub image: Image,
+    pub frames_stacked: usize,
+    pub frame_count: usize,
+}
+
+/// Runs the full SolHat process with the task's parameters. If `preview_sender` is
+/// given, snapshots of the stack are sent through it periodically while stacking.
+/// Completion is left to the caller, as a queue runs several of these as one task.
 pub async fn run_async(
-    master_sender: Sender<TaskStatusContainer>,
+    task: TaskContext,
+    preview_sender: Option<Sender<StackSnapshot>>,
     output_filename: PathBuf,
 ) -> Result<()> {
-    info!("Async task started");
+    info!("Async task {} started", task.id);
 
-    let mut context = build_solhat_context(&master_sender)?;
+    let params = &task.params;
+    let mut context = build_solhat_context(&task, true)?;
 
     /////////////////////////////////////////////////////////////
     /////////////////////////////////////////////////////////////
 
-    context.frame_records = frame_sigma_analysis(&context, master_sender.clone())?;
+    context.frame_records = frame_sigma_analysis(&context, &task)?;
 
     /////////////////////////////////////////////////////////////
     /////////////////////////////////////////////////////////////
 
-    context.frame_records = frame_limiting(&context, master_sender.clone())?;
+    context.frame_records = frame_limiting(&context, &task)?;
 
     /////////////////////////////////////////////////////////////
     /////////////////////////////////////////////////////////////
 
-    context.frame_records = frame_rotation(&context, master_sender.clone())?;
+    context.frame_records = frame_rotation(&mut context, &task)?;
 
     /////////////////////////////////////////////////////////////
     /////////////////////////////////////////////////////////////
@@ -46,21 +70,18 @@ pub async fn run_async(
     if context.frame_records.is_empty() {
         println!("Zero frames to stack. Cannot continue");
     } else {
-        let drizzle_output = drizzle_stacking(&context, master_sender.clone())?;
+        let drizzle_output = drizzle_stacking(&context, &task, preview_sender.clone())?;
 
-        check_cancel_status(&master_sender)?;
-        set_task_status(&master_sender, "Merging Stack Buffers", 0, 0);
+        task.check_cancel_status()?;
+        task.set_status("Merging Stack Buffers", 0, 0);
         let stacked_buffer = drizzle_output.get_finalized().unwrap();
 
-        let do_ld_correction = get_state_param!(ld_correction);
-        let solar_radius = get_state_param!(solar_radius_pixels);
-        let ld_coefficient = get_state_param!(ld_coefficient);
-        let mut corrected_buffer = if do_ld_correction {
-            set_task_status(&master_sender, "Applying Limb Correction", 0, 0);
+        let mut corrected_buffer = if params.ld_correction {
+            task.set_status("Applying Limb Correction", 0, 0);
             ldcorrect::limb_darkening_correction_on_image(
                 &stacked_buffer,
-                solar_radius,
-                &vec![ld_coefficient],
+                params.solar_radius_pixels,
+                &vec![params.ld_coefficient],
                 10.0,
                 false,
             )?
@@ -68,6 +89,11 @@ pub async fn run_async(
             stacked_buffer
         };
 
+        if params.deconvolution.enabled {
+            corrected_buffer =
+                deconvolve::richardson_lucy(&corrected_buffer, &params.deconvolution, &task)?;
+        }
+
         // Let the user know some stuff...
         let (stackmin, stackmax) = corrected_buffer.get_min_max_all_channel();
         info!(
@@ -77,144 +103,325 @@ pub async fn run_async(
             context.frame_records.len()
         );
 
-        set_task_status(&master_sender, "Normalizing Data", 0, 0);
-        if get_state_param!(decorrelated_colors) {
+        // FITS output keeps the stack values as they are
+        let unnormalized_buffer = params
+            .output_format
+            .is_unnormalized()
+            .then(|| corrected_buffer.clone());
+
+        task.set_status("Normalizing Data", 0, 0);
+        if params.decorrelated_colors {
             corrected_buffer.normalize_to_16bit_decorrelated();
         } else {
             corrected_buffer.normalize_to_16bit();
         }
 
-        set_task_status(&master_sender, "Saving to disk", 0, 0);
+        // The completed stack, before sharpening, so the UI can preview sharpening on it
+        if let Some(preview_sender) = &preview_sender {
+            // The window may have closed since, which shouldn't stop processing
+            if let Err(why) = preview_sender.send(StackSnapshot {
+                image: corrected_buffer.clone(),
+                frames_stacked: context.frame_records.len(),
+                frame_count: context.frame_records.len(),
+            }) {
+                warn!("Failed to send stack snapshot: {:?}", why);
+            }
+        }
+
+        if params.sharpening.enabled && unnormalized_buffer.is_some() {
+            warn!("Sharpening is not applied to unnormalized FITS output");
+        } else if params.sharpening.enabled {
+            task.check_cancel_status()?;
+            task.set_status("Sharpening", 0, 0);
+            corrected_buffer = sharpen::sharpen_image(&corrected_buffer, &params.sharpening)?;
+        }
+
+        task.set_status("Saving to disk", 0, 0);
         info!(
             "Final image size: {}, {}",
             corrected_buffer.width, corrected_buffer.height
         );
 
         // Save finalized image to disk
-        set_task_status(&master_sender, "Saving", 0, 0);
-        corrected_buffer.save(output_filename.to_string_lossy().as_ref())?;
+        task.set_status("Saving", 0, 0);
+        if let Some(unnormalized_buffer) = &unnormalized_buffer {
+            fits::save_image(
+                unnormalized_buffer,
+                &output_filename,
+                &output::fits_header(params, context.frame_records.len()),
+            )?;
+        } else {
+            output::save_normalized(&corrected_buffer, params.output_format, &output_filename)?;
+        }
+
+        // Record how the output was made alongside it
+        task.set_status("Saving Metadata", 0, 0);
+        RunMetadata::new(&context, params, &output_filename, stackmin, stackmax)
+            .save_sidecar(&output_filename)?;
 
         // The user will likely never see this actually appear on screen
-        set_task_status(&master_sender, "Done", 1, 1);
+        task.set_status("Done", 1, 1);
     }
 
-    set_task_completed(&master_sender);
-
     Ok(())
 }
 
-fn frame_sigma_analysis(
-    context: &ProcessContext,
-    sender: Sender<TaskStatusContainer>,
-) -> Result<Vec<FrameRecord>> {
-    check_cancel_status(&sender)?;
+fn frame_sigma_analysis(context: &ProcessContext, task: &TaskContext) -> Result<Vec<FrameRecord>> {
+    task.check_cancel_status()?;
 
     let frame_count = context.frame_records.len();
 
-    set_task_status(&sender, "Frame Analysis", frame_count, 0);
-
-    let counter = Arc::new(Mutex::new(0));
+    task.set_status("Frame Analysis", frame_count, 0);
 
-    let frame_records = frame_analysis_window_size(
-        context,
-        context.parameters.analysis_window_size,
-        move |fr| {
+    task.reset_progress();
+    let progress_task = task.clone();
+    let result =
+        frame_analysis_window_size(context, &task.params, &task.cancel_token(), move |fr| {
             info!(
                 "frame_sigma_analysis(): Frame processed with sigma {}",
                 fr.sigma
             );
-            // check_cancel_status(&sender);
 
-            let mut c = counter.lock().unwrap();
-            *c += 1;
-            set_task_status(&sender, "Frame Analysis", frame_count, *c)
-        },
-    )?;
+            let c = progress_task.advance_progress();
+            progress_task.set_status("Frame Analysis", frame_count, c)
+        });
 
-    Ok(frame_records)
+    // Reports the cancel if it stopped the analysis part way through
+    task.check_cancel_status()?;
+    Ok(result?.into_iter().map(|a| a.frame_record).collect())
 }
 
-fn frame_limiting(
-    context: &ProcessContext,
-    sender: Sender<TaskStatusContainer>,
-) -> Result<Vec<FrameRecord>> {
-    check_cancel_status(&sender)?;
+fn frame_limiting(context: &ProcessContext, task: &TaskContext) -> Result<Vec<FrameRecord>> {
+    task.check_cancel_status()?;
 
     let frame_count = context.frame_records.len();
 
-    set_task_status(&sender, "Applying Frame Limits", frame_count, 0);
-
-    let counter = Arc::new(Mutex::new(0));
-
-    let frame_records = frame_limit_determinate(context, move |_fr| {
-        info!("frame_limit_determinate(): Frame processed.");
-        // check_cancel_status(&sender);
-
-        let mut c = counter.lock().unwrap();
-        *c += 1;
-        set_task_status(&sender, "Applying Frame Limits", frame_count, *c)
-    })?;
+    task.set_status("Applying Frame Limits", frame_count, 0);
+
+    // Frames excluded by hand on the analysis chart are dropped first so the top
+    // percentage and maximum frame count are taken from the frames that remain
+    let frame_records: Vec<FrameRecord> = context
+        .frame_records
+        .iter()
+        .filter(|fr| {
+            !task
+                .params
+                .is_frame_excluded(&fr.source_file_id, fr.frame_id)
+        })
+        .cloned()
+        .collect();
+    if frame_records.len() < frame_count {
+        info!(
+            "frame_limiting(): {} frames excluded by hand",
+            frame_count - frame_records.len()
+        );
+    }
 
+    // Applied here rather than with SolHat's frame_limit_determinate, which can't be
+    // cancelled, the same way the analysis chart counts the frames to be stacked
+    let frame_records = apply_frame_limits(frame_records, &task.params, |fr| fr.sigma);
+    info!(
+        "frame_limiting(): {} of {} frames within limits",
+        frame_records.len(),
+        frame_count
+    );
+    task.set_status("Applying Frame Limits", frame_count, frame_count);
     Ok(frame_records)
 }
 
-fn frame_rotation(
-    context: &ProcessContext,
-    sender: Sender<TaskStatusContainer>,
-) -> Result<Vec<FrameRecord>> {
-    check_cancel_status(&sender)?;
+/// SolHat's rotation analysis can't be stopped part way through, so it's given a batch
+/// of frames at a time. Each frame's rotation only depends on its own timestamp.
+fn frame_rotation(context: &mut ProcessContext, task: &TaskContext) -> Result<Vec<FrameRecord>> {
+    task.check_cancel_status()?;
 
-    let frame_count = context.frame_records.len();
+    let frame_records = std::mem::take(&mut context.frame_records);
+    let frame_count = frame_records.len();
 
-    set_task_status(
-        &sender,
-        "Computing Parallactic Angle Rotations",
-        frame_count,
-        0,
-    );
+    task.set_status("Computing Parallactic Angle Rotations", frame_count, 0);
 
-    let counter = Arc::new(Mutex::new(0));
+    task.reset_progress();
+    let mut rotated: Vec<FrameRecord> = Vec::with_capacity(frame_count);
+    for batch in frame_records.chunks(ROTATION_BATCH_FRAMES) {
+        task.check_cancel_status()?;
+        context.frame_records = batch.to_vec();
+        let progress_task = task.clone();
+        rotated.extend(frame_rotation_analysis(context, move |fr| {
+            info!(
+                "Rotation for frame is {} degrees",
+                fr.computed_rotation.to_degrees()
+            );
 
-    let frame_records = frame_rotation_analysis(context, move |fr| {
-        info!(
-            "Rotation for frame is {} degrees",
-            fr.computed_rotation.to_degrees()
-        );
-        // check_cancel_status(&sender);
-
-        let mut c = counter.lock().unwrap();
-        *c += 1;
-        set_task_status(
-            &sender,
-            "Computing Parallactic Angle Rotations",
-            frame_count,
-            *c,
-        )
-    })?;
+            let c = progress_task.advance_progress();
+            progress_task.set_status("Computing Parallactic Angle Rotations", frame_count, c)
+        })?);
+    }
+    context.frame_records = frame_records;
 
-    Ok(frame_records)
+    task.check_cancel_status()?;
+    Ok(rotated)
 }
 
+/// Stacks the frames the same way as SolHat's `process_frame_stacking`, which can't be
+/// cancelled or previewed part way through. Loading and calibrating frames runs in
+/// parallel, while adding them to the drizzle buffer is serialized by its lock.
 fn drizzle_stacking(
     context: &ProcessContext,
-    sender: Sender<TaskStatusContainer>,
+    task: &TaskContext,
+    preview_sender: Option<Sender<StackSnapshot>>,
 ) -> Result<BilinearDrizzle> {
-    check_cancel_status(&sender)?;
+    task.check_cancel_status()?;
 
     let frame_count = context.frame_records.len();
 
-    set_task_status(&sender, "Stacking", frame_count, 0);
-
-    let counter = Arc::new(Mutex::new(0));
-
-    let drizzle_output = process_frame_stacking(context, move |_fr| {
-        info!("process_frame_stacking(): Frame processed.");
-        // check_cancel_status(&sender);
+    task.set_status("Stacking", frame_count, 0);
+
+    let first_frame = context.frame_records[0].get_frame(context)?;
+    let drizzle = Mutex::new(BilinearDrizzle::new(
+        first_frame.buffer.width,
+        first_frame.buffer.height,
+        context.parameters.drizzle_scale,
+        first_frame.buffer.num_bands(),
+    ));
+
+    let snapshot_interval = (frame_count / STACK_PREVIEW_COUNT).max(1);
+    task.reset_progress();
+
+    // Frames are loaded and calibrated in parallel, but added to the stack one at a time
+    let result = context
+        .frame_records
+        .par_iter()
+        .try_for_each(|fr| -> Result<()> {
+            if task.is_cancel_requested() {
+                return Err(TaskCompletion::Cancelled.into());
+            }
+            let frame = fr.get_frame(context)?;
+
+            let mut drizzle = drizzle.lock().unwrap();
+            drizzle.add_with_transform(&frame.buffer, fr.offset, fr.computed_rotation);
+            info!("drizzle_stacking(): Frame processed.");
+
+            let c = task.advance_progress();
+            task.set_status("Stacking", frame_count, c);
+
+            if let Some(preview_sender) = &preview_sender {
+                if c % snapshot_interval == 0 && c < frame_count {
+                    let mut image = drizzle.get_finalized()?;
+                    image.normalize_to_16bit();
+                    if let Err(why) = preview_sender.send(StackSnapshot {
+                        image,
+                        frames_stacked: c,
+                        frame_count,
+                    }) {
+                        warn!("Failed to send stack snapshot: {:?}", why);
+                    }
+                }
+            }
+            Ok(())
+        });
+
+    task.check_cancel_status()?;
+    result?;
+    Ok(drizzle.into_inner().unwrap())
+}
 
-        let mut c = counter.lock().unwrap();
-        *c += 1;
-        set_task_status(&sender, "Stacking", frame_count, *c)
-    })?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gtk::glib::{MainContext, Priority};
+    use solhat::calibrationframe::CalibrationImage;
+    use solhat::stacking::process_frame_stacking;
+    use std::fs;
+    use std::path::Path;
+
+    const WIDTH: usize = 32;
+    const HEIGHT: usize = 24;
+    const FRAME_COUNT: usize = 6;
+
+    /// Writes an 8 bit mono SER with a gradient that shifts from frame to frame
+    fn write_synthetic_ser(path: &Path) {
+        let mut bytes: Vec<u8> = b"LUCAM-RECORDER".to_vec();
+        [0, 0, 0, WIDTH as i32, HEIGHT as i32, 8, FRAME_COUNT as i32]
+            .iter()
+            .for_each(|v: &i32| bytes.extend_from_slice(&v.to_le_bytes()));
+        ["Observer", "Synthetic Camera", "Telescope"]
+            .iter()
+            .for_each(|s| {
+                let mut field = s.as_bytes().to_vec();
+                field.resize(40, b' ');
+                bytes.extend_from_slice(&field);
+            });
+        let start_time: i64 = 638_000_000_000_000_000;
+        bytes.extend_from_slice(&start_time.to_le_bytes());
+        bytes.extend_from_slice(&start_time.to_le_bytes());
+
+        (0..FRAME_COUNT).for_each(|f| {
+            (0..HEIGHT).for_each(|y| {
+                (0..WIDTH).for_each(|x| bytes.push(((x * 5 + y * 3 + f * 11) % 250) as u8));
+            });
+        });
+        (0..FRAME_COUNT).for_each(|f| {
+            let timestamp = start_time + f as i64 * 100_000;
+            bytes.extend_from_slice(&timestamp.to_le_bytes());
+        });
+        fs::write(path, bytes).expect("Failed to write test SER");
+    }
 
-    Ok(drizzle_output)
+    #[test]
+    fn drizzle_stacking_matches_solhat() {
+        let ser_path = std::env::temp_dir().join(format!(
+            "solhat_ui_stacking_test_{}.ser",
+            std::process::id()
+        ));
+        write_synthetic_ser(&ser_path);
+
+        let params_state = ParametersState {
+            lights: vec![ser_path.to_owned()],
+            ..Default::default()
+        };
+        let params = build_solhat_parameters(&params_state).unwrap();
+        let context = ProcessContext::create_with_calibration_frames(
+            &params,
+            CalibrationImage::new_empty(),
+            CalibrationImage::new_empty(),
+            CalibrationImage::new_empty(),
+            CalibrationImage::new_empty(),
+        )
+        .unwrap();
+        assert_eq!(context.frame_records.len(), FRAME_COUNT);
+
+        // The receiver is kept so status updates can be sent
+        let (sender, _receiver) = MainContext::channel(Priority::default());
+        let task = TaskContext::new(params_state, sender);
+        let stacked = drizzle_stacking(&context, &task, None)
+            .unwrap()
+            .get_finalized()
+            .unwrap();
+        let expected = process_frame_stacking(&context, |_fr| {})
+            .unwrap()
+            .get_finalized()
+            .unwrap();
+        fs::remove_file(&ser_path).ok();
+
+        assert_eq!(stacked.width, expected.width);
+        assert_eq!(stacked.height, expected.height);
+        assert_eq!(stacked.num_bands(), expected.num_bands());
+        (0..expected.num_bands()).for_each(|b| {
+            (0..expected.height).for_each(|y| {
+                (0..expected.width).for_each(|x| {
+                    let a = stacked.get_band(b).get(x, y);
+                    let e = expected.get_band(b).get(x, y);
+                    // Frames may be added in a different order
+                    assert!(
+                        (a - e).abs() <= 1e-3 * e.abs().max(1.0),
+                        "Pixel {},{} of band {} is {} rather than {}",
+                        x,
+                        y,
+                        b,
+                        a,
+                        e
+                    );
+                });
+            });
+        });
+    }
 }
@@ -0,0 +1,194 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use solhat::drizzle::Scale;
+use std::fs::File;
+use std::io::Write;
+
+use crate::analysis::quality::QualityMetric;
+use crate::state::{solhat_config_dir, ParametersState};
+
+/// A named set of processing values which tend to stay the same for a given
+/// telescope, camera and filter combination.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct ProcessingProfile {
+    pub name: String,
+    pub obj_detection_threshold: f64,
+    pub drizzle_scale: Scale,
+    pub max_frames: usize,
+    pub min_sigma: f64,
+    pub max_sigma: f64,
+    pub top_percentage: f64,
+    pub analysis_window_size: usize,
+    /// Kept with the sigma limits, as their scale depends on it
+    pub quality_metric: QualityMetric,
+    pub ld_correction: bool,
+    pub ld_coefficient: f64,
+    pub solar_radius_pixels: usize,
+}
+
+impl Default for ProcessingProfile {
+    fn default() -> Self {
+        ProcessingProfile::from_parameters("Default", &ParametersState::default())
+    }
+}
+
+impl ProcessingProfile {
+    /// Creates a profile from the current processing parameters
+    pub fn from_parameters(name: &str, params: &ParametersState) -> Self {
+        Self {
+            name: name.to_owned(),
+            obj_detection_threshold: params.obj_detection_threshold,
+            drizzle_scale: params.drizzle_scale,
+            max_frames: params.max_frames,
+            min_sigma: params.min_sigma,
+            max_sigma: params.max_sigma,
+            top_percentage: params.top_percentage,
+            analysis_window_size: params.analysis_window_size,
+            quality_metric: params.quality_metric,
+            ld_correction: params.ld_correction,
+            ld_coefficient: params.ld_coefficient,
+            solar_radius_pixels: params.solar_radius_pixels,
+        }
+    }
+}
+
+/// The collection of saved profiles, kept in `~/.solhat/profiles.toml`
+#[derive(Deserialize, Serialize, Default, Clone)]
+#[serde(default)]
+pub struct ProfileLibrary {
+    pub active: Option<String>,
+    pub profiles: Vec<ProcessingProfile>,
+}
+
+impl ProfileLibrary {
+    pub fn load_from_userhome() -> Result<Self> {
+        let profiles_file_path = dirs::home_dir().unwrap().join(".solhat/profiles.toml");
+        if profiles_file_path.exists() {
+            info!("Profiles file exists at path: {:?}", profiles_file_path);
+            let t = std::fs::read_to_string(profiles_file_path)?;
+            Ok(toml::from_str(&t)?)
+        } else {
+            warn!("Profiles file does not exist. Will be created on exit");
+            Err(anyhow!("Profiles file does not exist"))
+        }
+    }
+
+    pub fn save_to_userhome(&self) -> Result<()> {
+        let toml_str = toml::to_string(&self)?;
+        let profiles_file_path = solhat_config_dir()?.join("profiles.toml");
+        let mut f = File::create(profiles_file_path)?;
+        f.write_all(toml_str.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.profiles.iter().map(|p| p.name.to_owned()).collect()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ProcessingProfile> {
+        self.profiles.iter().find(|p| p.name == name)
+    }
+
+    fn get_mut(&mut self, name: &str) -> Option<&mut ProcessingProfile> {
+        self.profiles.iter_mut().find(|p| p.name == name)
+    }
+
+    pub fn get_active(&self) -> Option<&ProcessingProfile> {
+        if let Some(active) = &self.active {
+            self.get(active)
+        } else {
+            None
+        }
+    }
+
+    pub fn set_active(&mut self, name: &str) -> Result<()> {
+        if self.get(name).is_none() {
+            return Err(anyhow!("No profile named '{}'", name));
+        }
+        self.active = Some(name.to_owned());
+        Ok(())
+    }
+
+    /// Returns `base` if no profile has that name, otherwise `base` with the first
+    /// unused numeric suffix.
+    pub fn unique_name(&self, base: &str) -> String {
+        let mut name = base.to_owned();
+        let mut n = 2;
+        while self.get(&name).is_some() {
+            name = format!("{} {}", base, n);
+            n += 1;
+        }
+        name
+    }
+
+    /// Creates a new profile from `params` and makes it the active profile
+    pub fn create(&mut self, name: &str, params: &ParametersState) -> Result<()> {
+        if name.trim().is_empty() {
+            return Err(anyhow!("Profile name cannot be empty"));
+        }
+        if self.get(name).is_some() {
+            return Err(anyhow!("A profile named '{}' already exists", name));
+        }
+        self.profiles
+            .push(ProcessingProfile::from_parameters(name, params));
+        self.active = Some(name.to_owned());
+        Ok(())
+    }
+
+    /// Replaces the values in an existing profile with those in `params`
+    pub fn update(&mut self, name: &str, params: &ParametersState) -> Result<()> {
+        if let Some(profile) = self.get_mut(name) {
+            *profile = ProcessingProfile::from_parameters(name, params);
+            Ok(())
+        } else {
+            Err(anyhow!("No profile named '{}'", name))
+        }
+    }
+
+    pub fn rename(&mut self, name: &str, new_name: &str) -> Result<()> {
+        if new_name.trim().is_empty() {
+            return Err(anyhow!("Profile name cannot be empty"));
+        }
+        if name != new_name && self.get(new_name).is_some() {
+            return Err(anyhow!("A profile named '{}' already exists", new_name));
+        }
+        if let Some(profile) = self.get_mut(name) {
+            profile.name = new_name.to_owned();
+        } else {
+            return Err(anyhow!("No profile named '{}'", name));
+        }
+        if self.active.as_deref() == Some(name) {
+            self.active = Some(new_name.to_owned());
+        }
+        Ok(())
+    }
+
+    /// Copies a profile under a new, unused name and makes the copy active. Returns
+    /// the name of the copy.
+    pub fn duplicate(&mut self, name: &str) -> Result<String> {
+        if let Some(profile) = self.get(name) {
+            let mut copy = profile.clone();
+            copy.name = self.unique_name(&format!("{} Copy", name));
+            let copy_name = copy.name.to_owned();
+            self.profiles.push(copy);
+            self.active = Some(copy_name.to_owned());
+            Ok(copy_name)
+        } else {
+            Err(anyhow!("No profile named '{}'", name))
+        }
+    }
+
+    /// Removes a profile. If it was the active profile, the first remaining profile
+    /// becomes active.
+    pub fn delete(&mut self, name: &str) -> Result<()> {
+        if self.get(name).is_none() {
+            return Err(anyhow!("No profile named '{}'", name));
+        }
+        self.profiles.retain(|p| p.name != name);
+        if self.active.as_deref() == Some(name) {
+            self.active = self.profiles.first().map(|p| p.name.to_owned());
+        }
+        Ok(())
+    }
+}
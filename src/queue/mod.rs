@@ -0,0 +1,215 @@
+use gtk::glib::Sender;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::cancel::TaskCompletion;
+use crate::process::{self, StackSnapshot};
+use crate::state::ParametersState;
+use crate::task::TaskContext;
+
+///////////////////////////////////////////////////////
+/// Processing Job Queue
+///////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum JobStatus {
+    Pending,
+    Running,
+    Completed,
+    Failed(String),
+    Cancelled,
+}
+
+impl JobStatus {
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, JobStatus::Failed(_) | JobStatus::Cancelled)
+    }
+}
+
+/// A queued run of the SolHat process. The parameters are a snapshot taken when the
+/// job was queued, so later changes in the UI don't affect it.
+#[derive(Clone)]
+pub struct Job {
+    pub id: usize,
+    pub params: ParametersState,
+    pub output_filename: PathBuf,
+    pub status: JobStatus,
+}
+
+#[derive(Default)]
+pub struct JobQueue {
+    next_id: usize,
+    pub jobs: Vec<Job>,
+    pub running: bool,
+}
+
+impl JobQueue {
+    pub fn enqueue(&mut self, params: ParametersState, output_filename: PathBuf) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        info!("Queueing job {} with output {:?}", id, output_filename);
+        self.jobs.push(Job {
+            id,
+            params,
+            output_filename,
+            status: JobStatus::Pending,
+        });
+        id
+    }
+
+    fn index_of(&self, id: usize) -> Option<usize> {
+        self.jobs.iter().position(|j| j.id == id)
+    }
+
+    /// Removes a job from the queue. A running job can't be removed.
+    pub fn remove(&mut self, id: usize) {
+        if let Some(i) = self.index_of(id) {
+            if self.jobs[i].status != JobStatus::Running {
+                self.jobs.remove(i);
+            }
+        }
+    }
+
+    pub fn move_up(&mut self, id: usize) {
+        if let Some(i) = self.index_of(id) {
+            if i > 0 {
+                self.jobs.swap(i - 1, i);
+            }
+        }
+    }
+
+    pub fn move_down(&mut self, id: usize) {
+        if let Some(i) = self.index_of(id) {
+            if i + 1 < self.jobs.len() {
+                self.jobs.swap(i, i + 1);
+            }
+        }
+    }
+
+    /// Puts a failed or cancelled job back into a pending state
+    pub fn retry(&mut self, id: usize) {
+        if let Some(i) = self.index_of(id) {
+            if self.jobs[i].status.is_retryable() {
+                self.jobs[i].status = JobStatus::Pending;
+            }
+        }
+    }
+
+    pub fn set_status(&mut self, id: usize, status: JobStatus) {
+        if let Some(i) = self.index_of(id) {
+            self.jobs[i].status = status;
+        }
+    }
+
+    pub fn clear_completed(&mut self) {
+        self.jobs.retain(|j| j.status != JobStatus::Completed);
+    }
+
+    /// Returns a copy of the first pending job, marking it as running
+    fn take_next_pending(&mut self) -> Option<Job> {
+        if let Some(job) = self
+            .jobs
+            .iter_mut()
+            .find(|j| j.status == JobStatus::Pending)
+        {
+            job.status = JobStatus::Running;
+            Some(job.clone())
+        } else {
+            None
+        }
+    }
+}
+
+lazy_static! {
+    pub static ref QUEUE: Arc<Mutex<JobQueue>> = Arc::new(Mutex::new(JobQueue::default()));
+}
+
+/// Runs pending jobs one after another, as a single task, until there are none left or
+/// one is cancelled. `queue_sender` is notified whenever a job changes status, and the
+/// output and first light input of each completed job are sent through
+/// `result_sender`.
+pub async fn run_queue(
+    task: TaskContext,
+    preview_sender: Sender<StackSnapshot>,
+    result_sender: Sender<(PathBuf, Option<PathBuf>)>,
+    queue_sender: Sender<()>,
+) {
+    info!("Job queue started");
+    QUEUE.lock().unwrap().running = true;
+    queue_sender.send(()).expect("Failed to send queue update");
+
+    let mut failed_jobs: Vec<usize> = vec![];
+    loop {
+        let next_job = QUEUE.lock().unwrap().take_next_pending();
+        let job = match next_job {
+            Some(job) => job,
+            None => break,
+        };
+        queue_sender.send(()).expect("Failed to send queue update");
+
+        info!("Starting job {}", job.id);
+
+        // Each job runs in its own task so a panic only fails that job, rather than
+        // leaving the queue marked as running
+        let first_light = job.params.lights.first().cloned();
+        let job_task = task.with_params(job.params);
+        let job_preview_sender = preview_sender.clone();
+        let output_filename = job.output_filename;
+        let job_output_filename = output_filename.clone();
+        let result = tokio::spawn(async move {
+            process::run_async(job_task, Some(job_preview_sender), job_output_filename).await
+        })
+        .await;
+        let status = match result {
+            Ok(Ok(_)) => {
+                if let Err(why) = result_sender.send((output_filename, first_light)) {
+                    warn!("Failed to send result of job {}: {:?}", job.id, why);
+                }
+                JobStatus::Completed
+            }
+            Ok(Err(why)) => match why.downcast_ref::<TaskCompletion>() {
+                Some(TaskCompletion::Cancelled) => JobStatus::Cancelled,
+                _ => {
+                    error!("Job {} failed: {:?}", job.id, why);
+                    JobStatus::Failed(why.to_string())
+                }
+            },
+            Err(why) => {
+                error!("Job {} panicked: {:?}", job.id, why);
+                JobStatus::Failed(
+                    "Processing stopped unexpectedly. See the log for details.".to_owned(),
+                )
+            }
+        };
+        if matches!(status, JobStatus::Failed(_)) {
+            failed_jobs.push(job.id);
+        }
+
+        // A cancel applies to the whole queue, not just the current job
+        let stop = status == JobStatus::Cancelled;
+        QUEUE.lock().unwrap().set_status(job.id, status);
+        queue_sender.send(()).expect("Failed to send queue update");
+        if stop {
+            warn!("Job {} cancelled. Stopping queue", job.id);
+            break;
+        }
+    }
+
+    // A cancel has already been reported. Failures are reported once the rest of the
+    // queue has run, as reporting one finishes the task.
+    if !task.is_cancel_requested() {
+        if failed_jobs.is_empty() {
+            task.set_completed();
+        } else {
+            let job_ids: Vec<String> = failed_jobs.iter().map(|id| id.to_string()).collect();
+            task.set_failed(&format!(
+                "{} queued job(s) failed: {}. See the queue for details.",
+                failed_jobs.len(),
+                job_ids.join(", ")
+            ));
+        }
+    }
+    QUEUE.lock().unwrap().running = false;
+    queue_sender.send(()).expect("Failed to send queue update");
+    info!("Job queue finished");
+}
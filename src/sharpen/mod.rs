@@ -0,0 +1,245 @@
+use anyhow::Result;
+use itertools::iproduct;
+use rayon::prelude::*;
+use sciimg::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+
+///////////////////////////////////////////////////////
+/// Post-stack Sharpening
+///////////////////////////////////////////////////////
+
+/// Number of à-trous wavelet layers. Layer `n` holds detail at a scale of about 2^n pixels.
+pub const WAVELET_LAYERS: usize = 6;
+
+/// B3 spline kernel used for the à-trous smoothing
+const B3_KERNEL: [f32; 5] = [1.0 / 16.0, 4.0 / 16.0, 6.0 / 16.0, 4.0 / 16.0, 1.0 / 16.0];
+
+/// Converts a median absolute deviation into an estimate of the standard deviation
+const MAD_TO_SIGMA: f32 = 0.6745;
+
+/// Upper limit of a normalized 16-bit image
+const MAX_VALUE: f32 = 65535.0;
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum SharpenMethod {
+    #[default]
+    Wavelets,
+    UnsharpMask,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(default)]
+pub struct WaveletLayer {
+    /// Multiplier applied to the layer. 1.0 leaves it unchanged.
+    pub gain: f64,
+    /// Soft threshold, in estimated standard deviations of the layer's noise
+    pub denoise: f64,
+}
+
+impl Default for WaveletLayer {
+    fn default() -> Self {
+        Self {
+            gain: 1.0,
+            denoise: 0.0,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct SharpenParameters {
+    pub enabled: bool,
+    pub method: SharpenMethod,
+    pub layers: Vec<WaveletLayer>,
+    /// Gaussian sigma of the unsharp mask, in pixels
+    pub unsharp_radius: f64,
+    pub unsharp_amount: f64,
+}
+
+impl Default for SharpenParameters {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            method: SharpenMethod::Wavelets,
+            layers: vec![WaveletLayer::default(); WAVELET_LAYERS],
+            unsharp_radius: 2.0,
+            unsharp_amount: 0.5,
+        }
+    }
+}
+
+/// Reflects an out of bounds index back into `0..n`
+pub(crate) fn mirror(i: isize, n: usize) -> usize {
+    let n = n as isize;
+    let i = if i < 0 { -i } else { i };
+    let i = if i >= n { 2 * n - 2 - i } else { i };
+    i.clamp(0, n - 1) as usize
+}
+
+/// Applies a separable kernel horizontally then vertically, with the kernel taps
+/// `step` pixels apart
+pub(crate) fn convolve_separable(
+    data: &[f32],
+    width: usize,
+    height: usize,
+    kernel: &[f32],
+    step: usize,
+) -> Vec<f32> {
+    let half = (kernel.len() / 2) as isize;
+    let step = step as isize;
+
+    let mut horizontal = vec![0.0; data.len()];
+    horizontal
+        .par_chunks_mut(width)
+        .enumerate()
+        .for_each(|(y, row)| {
+            (0..width).for_each(|x| {
+                row[x] = kernel
+                    .iter()
+                    .enumerate()
+                    .map(|(k, w)| {
+                        let sx = mirror(x as isize + (k as isize - half) * step, width);
+                        w * data[y * width + sx]
+                    })
+                    .sum();
+            });
+        });
+
+    let mut vertical = vec![0.0; data.len()];
+    vertical
+        .par_chunks_mut(width)
+        .enumerate()
+        .for_each(|(y, row)| {
+            (0..width).for_each(|x| {
+                row[x] = kernel
+                    .iter()
+                    .enumerate()
+                    .map(|(k, w)| {
+                        let sy = mirror(y as isize + (k as isize - half) * step, height);
+                        w * horizontal[sy * width + x]
+                    })
+                    .sum();
+            });
+        });
+    vertical
+}
+
+/// Estimates the noise in a wavelet layer from its median absolute value
+fn estimate_noise(layer: &[f32]) -> f32 {
+    let mut abs: Vec<f32> = layer.iter().map(|v| v.abs()).collect();
+    if abs.is_empty() {
+        return 0.0;
+    }
+    let mid = abs.len() / 2;
+    let (_, median, _) =
+        abs.select_nth_unstable_by(mid, |a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+    *median / MAD_TO_SIGMA
+}
+
+fn wavelet_sharpen(data: &[f32], width: usize, height: usize, layers: &[WaveletLayer]) -> Vec<f32> {
+    let mut smoothed = data.to_vec();
+    let mut result = vec![0.0; data.len()];
+
+    layers.iter().enumerate().for_each(|(n, layer)| {
+        let next = convolve_separable(&smoothed, width, height, &B3_KERNEL, 1 << n);
+        let mut detail: Vec<f32> = smoothed
+            .iter()
+            .zip(next.iter())
+            .map(|(c, s)| c - s)
+            .collect();
+
+        if layer.denoise > 0.0 {
+            let threshold = layer.denoise as f32 * estimate_noise(&detail);
+            detail
+                .iter_mut()
+                .for_each(|d| *d = d.signum() * (d.abs() - threshold).max(0.0));
+        }
+
+        result
+            .iter_mut()
+            .zip(detail.iter())
+            .for_each(|(r, d)| *r += d * layer.gain as f32);
+        smoothed = next;
+    });
+
+    // Add back the residual, which is everything coarser than the last layer
+    result
+        .iter_mut()
+        .zip(smoothed.iter())
+        .for_each(|(r, s)| *r += s);
+    result
+}
+
+pub(crate) fn gaussian_kernel(sigma: f64) -> Vec<f32> {
+    let radius = (sigma * 3.0).ceil().max(1.0) as isize;
+    let kernel: Vec<f32> = (-radius..=radius)
+        .map(|i| (-(i * i) as f64 / (2.0 * sigma * sigma)).exp() as f32)
+        .collect();
+    let sum: f32 = kernel.iter().sum();
+    kernel.iter().map(|k| k / sum).collect()
+}
+
+fn unsharp_mask(data: &[f32], width: usize, height: usize, radius: f64, amount: f64) -> Vec<f32> {
+    let blurred = convolve_separable(data, width, height, &gaussian_kernel(radius), 1);
+    data.iter()
+        .zip(blurred.iter())
+        .map(|(v, b)| v + (v - b) * amount as f32)
+        .collect()
+}
+
+/// Sharpens each band of a normalized 16-bit image
+pub fn sharpen_image(image: &Image, params: &SharpenParameters) -> Result<Image> {
+    let (width, height) = (image.width, image.height);
+    let mut sharpened = image.clone();
+
+    let bands: Vec<Vec<f32>> = (0..image.num_bands())
+        .into_par_iter()
+        .map(|b| {
+            let band = image.get_band(b);
+            let data: Vec<f32> = iproduct!(0..height, 0..width)
+                .map(|(y, x)| band.get(x, y))
+                .collect();
+            match params.method {
+                SharpenMethod::Wavelets => wavelet_sharpen(&data, width, height, &params.layers),
+                SharpenMethod::UnsharpMask => unsharp_mask(
+                    &data,
+                    width,
+                    height,
+                    params.unsharp_radius,
+                    params.unsharp_amount,
+                ),
+            }
+        })
+        .collect();
+
+    bands.iter().enumerate().for_each(|(b, data)| {
+        iproduct!(0..height, 0..width).for_each(|(y, x)| {
+            sharpened.put(x, y, data[y * width + x].clamp(0.0, MAX_VALUE), b);
+        });
+    });
+    Ok(sharpened)
+}
+
+/// Copies a region of an image, limited to the image bounds
+pub fn crop_image(
+    image: &Image,
+    left: usize,
+    top: usize,
+    width: usize,
+    height: usize,
+) -> Result<Image> {
+    let left = left.min(image.width.saturating_sub(1));
+    let top = top.min(image.height.saturating_sub(1));
+    let width = width.min(image.width - left);
+    let height = height.min(image.height - top);
+
+    let mut cropped = Image::new_with_bands(width, height, image.num_bands(), ImageMode::U16BIT)?;
+    (0..image.num_bands()).for_each(|b| {
+        let band = image.get_band(b);
+        iproduct!(0..height, 0..width).for_each(|(y, x)| {
+            cropped.put(x, y, band.get(left + x, top + y), b);
+        });
+    });
+    Ok(cropped)
+}
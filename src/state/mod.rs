@@ -1,26 +1,39 @@
 use anyhow::Result;
-use gtk::glib::Sender;
-use serde::{Deserialize, Serialize};
-use solhat::calibrationframe::{CalibrationImage, ComputeMethod};
+use serde::{Deserialize, Deserializer, Serialize};
 use solhat::context::{ProcessContext, ProcessParameters};
 use solhat::drizzle::Scale;
 use solhat::target::Target;
 use std::fs::{self, File};
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
-use crate::cancel::*;
-use crate::taskstatus::*;
-
-/// Describes the parameters needed to run the SolHat algorithm
+use crate::analysis::quality::{AnalysisRegion, QualityMetric, RegionCombine};
+use crate::calibration::*;
+use crate::deconvolve::DeconvolutionParameters;
+use crate::output::pattern::DEFAULT_PATTERN;
+use crate::output::{OutputFormat, OverwritePolicy};
+use crate::profiles::ProfileLibrary;
+use crate::sharpen::SharpenParameters;
+use crate::task::TaskContext;
+
+/// Describes the parameters needed to run the SolHat algorithm. Missing values
+/// fall back to their defaults when deserialized.
 #[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
 pub struct ParametersState {
-    pub light: Option<PathBuf>,
+    /// Light inputs, stacked together in order. Earlier versions only kept a single
+    /// `light` path, which is still accepted when loading.
+    #[serde(alias = "light", deserialize_with = "deserialize_one_or_many")]
+    pub lights: Vec<PathBuf>,
     pub dark: Option<PathBuf>,
     pub flat: Option<PathBuf>,
     pub darkflat: Option<PathBuf>,
     pub bias: Option<PathBuf>,
+    pub dark_method: CombineMethod,
+    pub flat_method: CombineMethod,
+    pub darkflat_method: CombineMethod,
+    pub bias_method: CombineMethod,
     pub hot_pixel_map: Option<PathBuf>,
     pub output_dir: Option<PathBuf>,
     pub freetext: String,
@@ -35,21 +48,59 @@ pub struct ParametersState {
     pub top_percentage: f64,
     pub decorrelated_colors: bool,
     pub analysis_window_size: usize,
+    /// How frame sharpness is measured during analysis
+    pub quality_metric: QualityMetric,
+    /// How the sigmas of the analysis windows are combined into a frame's sigma
+    pub region_combine: RegionCombine,
     pub ld_correction: bool,
     pub ld_coefficient: f64,
     pub solar_radius_pixels: usize,
     pub vert_offset: i32,
     pub horiz_offset: i32,
+
+    /// Save computed calibration masters into the calibration library
+    pub save_masters: bool,
+    pub master_format: MasterFormat,
+
+    pub output_format: OutputFormat,
+    /// Output filename, without extension, with tokens like `{stem}` and `{date}`
+    pub output_pattern: String,
+    pub overwrite_policy: OverwritePolicy,
+
+    /// Optional deconvolution applied after limb darkening correction
+    pub deconvolution: DeconvolutionParameters,
+
+    /// Optional sharpening applied to the normalized stack
+    pub sharpening: SharpenParameters,
+
+    /// Analysis windows placed on the light preview. Without any, frames are analyzed
+    /// at the disk center.
+    pub analysis_regions: Vec<AnalysisRegion>,
+
+    /// Frames dropped by hand from the analysis chart, such as those with a satellite
+    /// or plane transit
+    pub excluded_frames: Vec<FrameExclusion>,
+}
+
+/// Identifies a single frame of one of the light inputs
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct FrameExclusion {
+    pub source_file_id: String,
+    pub frame_id: usize,
 }
 
 impl Default for ParametersState {
     fn default() -> Self {
         Self {
-            light: Default::default(),
+            lights: Default::default(),
             dark: Default::default(),
             flat: Default::default(),
             darkflat: Default::default(),
             bias: Default::default(),
+            dark_method: CombineMethod::Mean,
+            flat_method: CombineMethod::Mean,
+            darkflat_method: CombineMethod::Mean,
+            bias_method: CombineMethod::Mean,
             hot_pixel_map: Default::default(),
             output_dir: Default::default(),
             freetext: Default::default(),
@@ -64,13 +115,104 @@ impl Default for ParametersState {
             top_percentage: 10.0,
             decorrelated_colors: false,
             analysis_window_size: 128,
+            quality_metric: QualityMetric::PointEstimation,
+            region_combine: RegionCombine::Mean,
             ld_correction: false,
             ld_coefficient: 0.56,
             solar_radius_pixels: 768,
             vert_offset: 0,
             horiz_offset: 0,
+            save_masters: false,
+            master_format: MasterFormat::Fits,
+            output_format: OutputFormat::Tiff,
+            output_pattern: DEFAULT_PATTERN.to_owned(),
+            overwrite_policy: OverwritePolicy::Prompt,
+            deconvolution: DeconvolutionParameters::default(),
+            sharpening: SharpenParameters::default(),
+            analysis_regions: vec![],
+            excluded_frames: vec![],
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(PathBuf),
+    Many(Vec<PathBuf>),
+}
+
+fn deserialize_one_or_many<'de, D>(deserializer: D) -> Result<Vec<PathBuf>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(p) => vec![p],
+        OneOrMany::Many(v) => v,
+    })
+}
+
+impl ParametersState {
+    /// All input file paths which have been set
+    pub fn input_paths(&self) -> Vec<PathBuf> {
+        let mut paths = self.lights.clone();
+        [
+            &self.dark,
+            &self.flat,
+            &self.darkflat,
+            &self.bias,
+            &self.hot_pixel_map,
+        ]
+        .iter()
+        .for_each(|p| {
+            if let Some(p) = p {
+                paths.push(p.to_owned());
+            }
+        });
+        paths
+    }
+
+    pub fn is_frame_excluded(&self, source_file_id: &str, frame_id: usize) -> bool {
+        self.excluded_frames
+            .iter()
+            .any(|e| e.source_file_id == source_file_id && e.frame_id == frame_id)
+    }
+
+    /// Excludes the frame, or includes it again if it's already excluded. Returns
+    /// whether the frame is now excluded.
+    pub fn toggle_frame_exclusion(&mut self, source_file_id: &str, frame_id: usize) -> bool {
+        if self.is_frame_excluded(source_file_id, frame_id) {
+            self.excluded_frames
+                .retain(|e| !(e.source_file_id == source_file_id && e.frame_id == frame_id));
+            false
+        } else {
+            self.excluded_frames.push(FrameExclusion {
+                source_file_id: source_file_id.to_owned(),
+                frame_id,
+            });
+            true
         }
     }
+
+    /// Drops exclusions of frames from files which are no longer light inputs, so they
+    /// don't carry over to unrelated captures
+    pub fn drop_stale_exclusions(&mut self) {
+        let lights: Vec<String> = self
+            .lights
+            .iter()
+            .map(|p| p.to_string_lossy().to_string())
+            .collect();
+        self.excluded_frames
+            .retain(|e| lights.contains(&e.source_file_id));
+    }
+
+    /// Loads a standalone parameters TOML file, such as the `[params]` table from
+    /// `shconfig.toml`
+    pub fn load_from_file(file_path: &Path) -> Result<Self> {
+        info!("Loading parameters from {:?}", file_path);
+        let t = std::fs::read_to_string(file_path)?;
+        Ok(toml::from_str(&t)?)
+    }
 }
 
 /// Describes the state of the UI
@@ -83,6 +225,20 @@ pub struct UiState {
 pub struct ApplicationState {
     pub params: ParametersState,
     pub ui: UiState,
+
+    /// Saved processing profiles. These are kept in their own file, so aren't
+    /// serialized with the rest of the application state.
+    #[serde(skip)]
+    pub profiles: ProfileLibrary,
+}
+
+/// Returns the path to `~/.solhat/`, creating it if needed.
+pub fn solhat_config_dir() -> Result<PathBuf> {
+    let solhat_config_dir = dirs::home_dir().unwrap().join(".solhat/");
+    if !solhat_config_dir.exists() {
+        fs::create_dir(&solhat_config_dir)?;
+    }
+    Ok(solhat_config_dir)
 }
 
 impl ApplicationState {
@@ -103,14 +259,11 @@ impl ApplicationState {
 
     pub fn save_to_userhome(&self) -> Result<()> {
         let toml_str = toml::to_string(&self).unwrap();
-        let solhat_config_dir = dirs::home_dir().unwrap().join(".solhat/");
-        if !solhat_config_dir.exists() {
-            fs::create_dir(&solhat_config_dir)?;
-        }
-        let config_file_path = solhat_config_dir.join("shconfig.toml");
+        let config_file_path = solhat_config_dir()?.join("shconfig.toml");
         let mut f = File::create(config_file_path)?;
         f.write_all(toml_str.as_bytes())?;
         debug!("{}", toml_str);
+        self.profiles.save_to_userhome()?;
         Ok(())
     }
 
@@ -133,7 +286,8 @@ impl ApplicationState {
     /// Checks each path option and if the path no longer exists on the filesystem,
     /// replace it with None
     pub fn validate_paths(&mut self) {
-        self.params.light = ApplicationState::validate_path(&self.params.light);
+        self.params.lights.retain(|p| p.exists());
+        self.params.drop_stale_exclusions();
         self.params.dark = ApplicationState::validate_path(&self.params.dark);
         self.params.flat = ApplicationState::validate_path(&self.params.flat);
         self.params.darkflat = ApplicationState::validate_path(&self.params.darkflat);
@@ -146,6 +300,8 @@ impl ApplicationState {
 lazy_static! {
     // Oh, this is such a hacky way to do it I hate it so much.
     // TODO: Learn the correct way to do this.
+    // Only the user interface uses this. Tasks work on their own snapshot of the
+    // parameters in a TaskContext.
     pub static ref STATE: Arc<Mutex<ApplicationState>> = Arc::new(Mutex::new(ApplicationState::default()));
 }
 
@@ -203,79 +359,89 @@ macro_rules! p2s {
     };
 }
 
-pub fn build_solhat_parameters() -> Result<ProcessParameters> {
-    let state = STATE.lock().unwrap();
-
-    if state.params.light.is_none() {
+/// Translates the parameters state into the parameters used by the SolHat library
+pub fn build_solhat_parameters(params: &ParametersState) -> Result<ProcessParameters> {
+    if params.lights.is_empty() {
         return Err(anyhow!("No light input identified"));
     }
     Ok(ProcessParameters {
-        input_files: vec![p2s!(state.params.light).unwrap()],
-        obj_detection_threshold: state.params.obj_detection_threshold,
-        obs_latitude: state.params.obs_latitude,
-        obs_longitude: state.params.obs_longitude,
-        target: state.params.target,
+        input_files: params
+            .lights
+            .iter()
+            .map(|p| p.to_string_lossy().to_string())
+            .collect(),
+        obj_detection_threshold: params.obj_detection_threshold,
+        obs_latitude: params.obs_latitude,
+        obs_longitude: params.obs_longitude,
+        target: params.target,
         crop_width: None,
         crop_height: None,
-        max_frames: Some(state.params.max_frames),
-        min_sigma: Some(state.params.min_sigma),
-        max_sigma: Some(state.params.max_sigma),
-        top_percentage: Some(state.params.top_percentage),
-        drizzle_scale: state.params.drizzle_scale,
+        max_frames: Some(params.max_frames),
+        min_sigma: Some(params.min_sigma),
+        max_sigma: Some(params.max_sigma),
+        top_percentage: Some(params.top_percentage),
+        drizzle_scale: params.drizzle_scale,
         initial_rotation: 0.0,
-        flat_inputs: p2s!(state.params.flat),
-        dark_inputs: p2s!(state.params.dark),
-        darkflat_inputs: p2s!(state.params.darkflat),
-        bias_inputs: p2s!(state.params.bias),
-        hot_pixel_map: p2s!(state.params.hot_pixel_map),
-        analysis_window_size: state.params.analysis_window_size,
-        vert_offset: state.params.vert_offset,
-        horiz_offset: state.params.horiz_offset,
+        flat_inputs: p2s!(params.flat),
+        dark_inputs: p2s!(params.dark),
+        darkflat_inputs: p2s!(params.darkflat),
+        bias_inputs: p2s!(params.bias),
+        hot_pixel_map: p2s!(params.hot_pixel_map),
+        analysis_window_size: params.analysis_window_size,
+        vert_offset: params.vert_offset,
+        horiz_offset: params.horiz_offset,
     })
 }
 
-pub fn build_solhat_context(sender: &Sender<TaskStatusContainer>) -> Result<ProcessContext> {
-    let params = build_solhat_parameters()?;
-
-    set_task_status(sender, "Processing Master Flat", 0, 0);
-    let master_flat = if let Some(inputs) = &params.flat_inputs {
-        info!("Processing master flat...");
-        CalibrationImage::new_from_file(inputs, ComputeMethod::Mean)?
-    } else {
-        CalibrationImage::new_empty()
-    };
+/// Creates the SolHat process context with its calibration masters. Masters computed
+/// along the way are only saved into the library when `save_masters` is set.
+pub fn build_solhat_context(task: &TaskContext, save_masters: bool) -> Result<ProcessContext> {
+    let params_state = &task.params;
+    let params = build_solhat_parameters(params_state)?;
+
+    task.set_status("Processing Master Flat", 0, 0);
+    let master_flat = load_or_compute_master(
+        CalibrationKind::Flat,
+        &params.flat_inputs,
+        params_state.flat_method,
+        params_state,
+        save_masters,
+    )?;
 
-    check_cancel_status(sender)?;
+    task.check_cancel_status()?;
 
-    set_task_status(sender, "Processing Master Dark Flat", 0, 0);
-    let master_darkflat = if let Some(inputs) = &params.darkflat_inputs {
-        info!("Processing master dark flat...");
-        CalibrationImage::new_from_file(inputs, ComputeMethod::Mean)?
-    } else {
-        CalibrationImage::new_empty()
-    };
+    task.set_status("Processing Master Dark Flat", 0, 0);
+    let master_darkflat = load_or_compute_master(
+        CalibrationKind::DarkFlat,
+        &params.darkflat_inputs,
+        params_state.darkflat_method,
+        params_state,
+        save_masters,
+    )?;
 
-    check_cancel_status(sender)?;
+    task.check_cancel_status()?;
 
-    set_task_status(sender, "Processing Master Dark", 0, 0);
-    let master_dark = if let Some(inputs) = &params.dark_inputs {
-        info!("Processing master dark...");
-        CalibrationImage::new_from_file(inputs, ComputeMethod::Mean)?
-    } else {
-        CalibrationImage::new_empty()
-    };
+    task.set_status("Processing Master Dark", 0, 0);
+    let master_dark = load_or_compute_master(
+        CalibrationKind::Dark,
+        &params.dark_inputs,
+        params_state.dark_method,
+        params_state,
+        save_masters,
+    )?;
 
-    check_cancel_status(sender)?;
+    task.check_cancel_status()?;
 
-    set_task_status(sender, "Processing Master Bias", 0, 0);
-    let master_bias = if let Some(inputs) = &params.bias_inputs {
-        info!("Processing master bias...");
-        CalibrationImage::new_from_file(inputs, ComputeMethod::Mean)?
-    } else {
-        CalibrationImage::new_empty()
-    };
+    task.set_status("Processing Master Bias", 0, 0);
+    let master_bias = load_or_compute_master(
+        CalibrationKind::Bias,
+        &params.bias_inputs,
+        params_state.bias_method,
+        params_state,
+        save_masters,
+    )?;
 
-    check_cancel_status(sender)?;
+    task.check_cancel_status()?;
 
     info!("Creating process context struct");
     let context = ProcessContext::create_with_calibration_frames(
@@ -0,0 +1,108 @@
+use gtk::glib::Sender;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::cancel::*;
+use crate::state::ParametersState;
+use crate::taskstatus::*;
+
+///////////////////////////////////////////////////////
+/// Per-task Context
+///////////////////////////////////////////////////////
+
+static NEXT_TASK_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// Everything a running task owns: a snapshot of the parameters, its cancel token and
+/// progress counter, and the channel it reports status on. Tasks running at the same
+/// time each have their own, so they can't cancel or miscount each other. Clones are
+/// the same task and share the token and counter.
+#[derive(Clone)]
+pub struct TaskContext {
+    pub id: usize,
+    pub params: ParametersState,
+    cancel: CancelToken,
+    progress: Arc<AtomicUsize>,
+    cancel_reported: Arc<AtomicBool>,
+    sender: Sender<TaskStatusContainer>,
+}
+
+impl TaskContext {
+    pub fn new(params: ParametersState, sender: Sender<TaskStatusContainer>) -> Self {
+        TaskContext {
+            id: NEXT_TASK_ID.fetch_add(1, Ordering::SeqCst),
+            params,
+            cancel: CancelToken::default(),
+            progress: Arc::new(AtomicUsize::new(0)),
+            cancel_reported: Arc::new(AtomicBool::new(false)),
+            sender,
+        }
+    }
+
+    /// The same task working on different parameters, such as the next job in the queue
+    pub fn with_params(&self, params: ParametersState) -> Self {
+        TaskContext {
+            params,
+            ..self.clone()
+        }
+    }
+
+    /// A token the user interface can use to cancel this task
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+
+    pub fn is_cancel_requested(&self) -> bool {
+        self.cancel.is_cancel_requested()
+    }
+
+    /// Returns `TaskCompletion::Cancelled` if a cancel has been requested, letting the
+    /// user interface know the first time
+    pub fn check_cancel_status(&self) -> Result<TaskCompletion, TaskCompletion> {
+        if self.is_cancel_requested() {
+            if !self.cancel_reported.swap(true, Ordering::SeqCst) {
+                warn!(
+                    "Task {} cancellation request detected. Stopping progress",
+                    self.id
+                );
+                self.send(Some(TaskStatus::TaskCancelled));
+            }
+            Err(TaskCompletion::Cancelled)
+        } else {
+            Ok(TaskCompletion::Completed)
+        }
+    }
+
+    pub fn reset_progress(&self) {
+        self.progress.store(0, Ordering::SeqCst);
+    }
+
+    /// Counts one more unit of work done, returning the new count
+    pub fn advance_progress(&self) -> usize {
+        self.progress.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    pub fn set_status(&self, task_name: &str, num_parts: usize, progress: usize) {
+        self.send(Some(TaskStatus::TaskPercentage(
+            task_name.to_owned(),
+            num_parts,
+            progress,
+        )));
+    }
+
+    pub fn set_completed(&self) {
+        self.send(None);
+    }
+
+    pub fn set_failed(&self, message: &str) {
+        self.send(Some(TaskStatus::TaskFailed(message.to_owned())));
+    }
+
+    fn send(&self, status: Option<TaskStatus>) {
+        self.sender
+            .send(TaskStatusContainer {
+                task_id: self.id,
+                status,
+            })
+            .expect("Failed to sent task status");
+    }
+}
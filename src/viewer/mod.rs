@@ -0,0 +1,652 @@
+use gtk::gdk::prelude::GdkCairoContextExt;
+use gtk::gdk_pixbuf::Pixbuf;
+use gtk::glib;
+use gtk::prelude::*;
+use gtk::{
+    cairo, Builder, Button, DrawingArea, EventControllerMotion, GestureClick, GestureDrag, Label,
+    Orientation, Scale, ToggleButton,
+};
+use sciimg::prelude::*;
+use solhat::ser::{SerFile, SerFrame};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Duration;
+
+use crate::analysis::quality::AnalysisRegion;
+use crate::analysis::sigma::region_color;
+use crate::conversion::image_to_picture;
+use crate::state::ParametersState;
+
+///////////////////////////////////////////////////////
+/// Zoomable & Pannable Image Preview
+///////////////////////////////////////////////////////
+
+/// Delay between frames when playing through a SER file
+const PLAYBACK_INTERVAL_MS: u64 = 100;
+
+/// How far, in screen pixels, the pointer can move between press and release for it
+/// to still count as a click rather than a pan
+const CLICK_MOVE_TOLERANCE: f64 = 4.0;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Zoom {
+    /// Scale the whole image to fit the view
+    Fit,
+    /// Fixed scale, where 1.0 is one image pixel per screen pixel
+    Scale(f64),
+}
+
+/// Analysis windows drawn over the image, which can be placed and removed by clicking
+struct AnalysisWindows {
+    regions: Vec<AnalysisRegion>,
+    window_size: usize,
+    obj_detection_threshold: f64,
+    /// Disk center of mass of the shown image, which the windows are placed from
+    center: Option<(f64, f64)>,
+    /// Called with the windows after one is added or removed
+    on_changed: Rc<dyn Fn(&[AnalysisRegion])>,
+}
+
+struct ViewerState {
+    /// The image as loaded, used for the pixel readout
+    image: Option<Image>,
+    /// 8-bit copy of the image for display
+    pixbuf: Option<Pixbuf>,
+    zoom: Zoom,
+    /// Image coordinate at the top left of the view when zoomed
+    view_x: f64,
+    view_y: f64,
+    drag_start: (f64, f64),
+    /// The SER file being browsed, if the image is one of its frames
+    ser_path: Option<PathBuf>,
+    ser_file: Option<SerFile>,
+    frame_count: usize,
+    frame_index: usize,
+    playback: Option<glib::SourceId>,
+    windows: Option<AnalysisWindows>,
+}
+
+impl ViewerState {
+    /// Returns the scale and the image coordinate at the top left corner of the view
+    fn transform(&self, width: f64, height: f64) -> (f64, f64, f64) {
+        let (image_width, image_height) = match &self.image {
+            Some(image) => (image.width as f64, image.height as f64),
+            None => return (1.0, 0.0, 0.0),
+        };
+        match self.zoom {
+            Zoom::Fit => {
+                let scale = (width / image_width).min(height / image_height);
+                (
+                    scale,
+                    (image_width - width / scale) / 2.0,
+                    (image_height - height / scale) / 2.0,
+                )
+            }
+            Zoom::Scale(scale) => (scale, self.view_x, self.view_y),
+        }
+    }
+
+    /// Converts a position in the view to image pixel coordinates, if it's on the image
+    fn view_to_image(&self, x: f64, y: f64, width: f64, height: f64) -> Option<(usize, usize)> {
+        let image = self.image.as_ref()?;
+        let (scale, view_x, view_y) = self.transform(width, height);
+        let image_x = (x / scale + view_x).floor();
+        let image_y = (y / scale + view_y).floor();
+        if image_x >= 0.0
+            && image_y >= 0.0
+            && (image_x as usize) < image.width
+            && (image_y as usize) < image.height
+        {
+            Some((image_x as usize, image_y as usize))
+        } else {
+            None
+        }
+    }
+
+    /// Finds the disk center of mass of the image, for placing the analysis windows
+    fn find_disk_center(&mut self) {
+        if let (Some(image), Some(windows)) = (&self.image, &mut self.windows) {
+            let offset =
+                image.calc_center_of_mass_offset(windows.obj_detection_threshold as f32, 0);
+            windows.center = Some((
+                image.width as f64 / 2.0 + offset.h as f64,
+                image.height as f64 / 2.0 + offset.v as f64,
+            ));
+        }
+    }
+}
+
+/// Outlines each analysis window in the color of its series on the analysis chart, and
+/// marks the disk center they're placed from. Expects the context to be scaled to the
+/// image, so lines and text are scaled back to keep their size on screen.
+fn draw_analysis_windows(
+    cr: &cairo::Context,
+    windows: &AnalysisWindows,
+    scale: f64,
+    view_x: f64,
+    view_y: f64,
+) {
+    let (center_x, center_y) = match windows.center {
+        Some(center) => (center.0 - view_x, center.1 - view_y),
+        None => return,
+    };
+    cr.set_line_width(2.0 / scale);
+    cr.set_font_size(14.0 / scale);
+
+    let arm = 6.0 / scale;
+    cr.set_source_rgb(1.0, 1.0, 1.0);
+    cr.move_to(center_x - arm, center_y);
+    cr.line_to(center_x + arm, center_y);
+    cr.move_to(center_x, center_y - arm);
+    cr.line_to(center_x, center_y + arm);
+    if let Err(why) = cr.stroke() {
+        error!("Failed to draw disk center: {:?}", why);
+    }
+
+    let size = windows.window_size as f64;
+    windows.regions.iter().enumerate().for_each(|(i, region)| {
+        let (r, g, b) = region_color(i);
+        cr.set_source_rgb(r as f64 / 255.0, g as f64 / 255.0, b as f64 / 255.0);
+        let left = center_x + region.offset_x - size / 2.0;
+        let top = center_y + region.offset_y - size / 2.0;
+        cr.rectangle(left, top, size, size);
+        if let Err(why) = cr.stroke() {
+            error!("Failed to draw analysis window: {:?}", why);
+        }
+        cr.move_to(left, top - 4.0 / scale);
+        if let Err(why) = cr.show_text(&format!("{}", i + 1)) {
+            error!("Failed to label analysis window: {:?}", why);
+        }
+    });
+}
+
+/// An image preview with a toolbar to fit or zoom the image, drag panning, and a
+/// readout of the raw pixel values under the cursor.
+#[derive(Clone)]
+pub struct PreviewViewer {
+    area: DrawingArea,
+    lbl_readout: Label,
+    scrubber: gtk::Box,
+    scl_frame: Scale,
+    btn_play: Button,
+    lbl_frame: Label,
+    btn_windows: ToggleButton,
+    btn_clear_windows: Button,
+    state: Rc<RefCell<ViewerState>>,
+}
+
+thread_local! {
+    static VIEWERS: RefCell<HashMap<String, PreviewViewer>> = RefCell::new(HashMap::new());
+}
+
+impl PreviewViewer {
+    fn new(container: &gtk::Box) -> Self {
+        let state = Rc::new(RefCell::new(ViewerState {
+            image: None,
+            pixbuf: None,
+            zoom: Zoom::Fit,
+            view_x: 0.0,
+            view_y: 0.0,
+            drag_start: (0.0, 0.0),
+            ser_path: None,
+            ser_file: None,
+            frame_count: 0,
+            frame_index: 0,
+            playback: None,
+            windows: None,
+        }));
+
+        let toolbar = gtk::Box::new(Orientation::Horizontal, 2);
+        let btn_fit = Button::with_label("Fit");
+        let btn_100 = Button::with_label("100%");
+        let btn_200 = Button::with_label("200%");
+        // Analysis window placement, only shown for the light preview
+        let btn_windows = ToggleButton::with_label("Place Windows");
+        btn_windows.set_tooltip_text(Some(
+            "Click the preview to place an analysis window, or click a window to remove it",
+        ));
+        btn_windows.set_visible(false);
+        let btn_clear_windows = Button::with_label("Clear Windows");
+        btn_clear_windows.set_visible(false);
+        let lbl_readout = Label::new(None);
+        lbl_readout.set_hexpand(true);
+        lbl_readout.set_xalign(1.0);
+        toolbar.append(&btn_fit);
+        toolbar.append(&btn_100);
+        toolbar.append(&btn_200);
+        toolbar.append(&btn_windows);
+        toolbar.append(&btn_clear_windows);
+        toolbar.append(&lbl_readout);
+
+        let area = DrawingArea::new();
+        area.set_hexpand(true);
+        area.set_vexpand(true);
+
+        // Frame scrubber, only shown for SER files
+        let scrubber = gtk::Box::new(Orientation::Horizontal, 2);
+        let btn_play = Button::from_icon_name("media-playback-start");
+        let scl_frame = Scale::with_range(Orientation::Horizontal, 0.0, 1.0, 1.0);
+        scl_frame.set_digits(0);
+        scl_frame.set_hexpand(true);
+        let lbl_frame = Label::new(None);
+        scrubber.append(&btn_play);
+        scrubber.append(&scl_frame);
+        scrubber.append(&lbl_frame);
+        scrubber.set_visible(false);
+
+        container.append(&toolbar);
+        container.append(&area);
+        container.append(&scrubber);
+
+        area.set_draw_func(glib::clone!(@strong state => move |_, cr, width, height| {
+            let state = state.borrow();
+            if let Some(pixbuf) = &state.pixbuf {
+                let (scale, view_x, view_y) = state.transform(width as f64, height as f64);
+                cr.scale(scale, scale);
+                cr.set_source_pixbuf(pixbuf, -view_x, -view_y);
+                // Show individual pixels when zoomed in rather than smoothing them
+                if scale >= 1.0 {
+                    cr.source().set_filter(cairo::Filter::Nearest);
+                }
+                if let Err(why) = cr.paint() {
+                    error!("Failed to draw preview: {:?}", why);
+                }
+                if let Some(windows) = &state.windows {
+                    draw_analysis_windows(cr, windows, scale, view_x, view_y);
+                }
+            }
+        }));
+
+        let viewer = PreviewViewer {
+            area: area.clone(),
+            lbl_readout: lbl_readout.clone(),
+            scrubber,
+            scl_frame: scl_frame.clone(),
+            btn_play: btn_play.clone(),
+            lbl_frame,
+            btn_windows,
+            btn_clear_windows: btn_clear_windows.clone(),
+            state: state.clone(),
+        };
+
+        scl_frame.connect_value_changed(glib::clone!(@strong viewer => move |scl| {
+            viewer.show_frame(scl.value() as usize);
+        }));
+        btn_play.connect_clicked(glib::clone!(@strong viewer => move |_| {
+            viewer.toggle_playback();
+        }));
+
+        btn_fit.connect_clicked(glib::clone!(@strong viewer => move |_| {
+            viewer.set_zoom(Zoom::Fit);
+        }));
+        btn_100.connect_clicked(glib::clone!(@strong viewer => move |_| {
+            viewer.set_zoom(Zoom::Scale(1.0));
+        }));
+        btn_200.connect_clicked(glib::clone!(@strong viewer => move |_| {
+            viewer.set_zoom(Zoom::Scale(2.0));
+        }));
+
+        let drag = GestureDrag::new();
+        drag.connect_drag_begin(glib::clone!(@strong state => move |_, _, _| {
+            let mut state = state.borrow_mut();
+            state.drag_start = (state.view_x, state.view_y);
+        }));
+        drag.connect_drag_update(
+            glib::clone!(@strong state, @weak area => move |_, offset_x, offset_y| {
+                let mut state = state.borrow_mut();
+                if let Zoom::Scale(scale) = state.zoom {
+                    state.view_x = state.drag_start.0 - offset_x / scale;
+                    state.view_y = state.drag_start.1 - offset_y / scale;
+                    area.queue_draw();
+                }
+            }),
+        );
+        area.add_controller(drag);
+
+        let motion = EventControllerMotion::new();
+        motion.connect_motion(glib::clone!(@strong viewer => move |_, x, y| {
+            viewer.update_readout(x, y);
+        }));
+        motion.connect_leave(glib::clone!(@weak lbl_readout => move |_| {
+            lbl_readout.set_label("");
+        }));
+        area.add_controller(motion);
+
+        // Releasing a pan doesn't place or remove a window
+        let click = GestureClick::new();
+        let press_position = Rc::new(Cell::new((0.0, 0.0)));
+        click.connect_pressed(glib::clone!(@strong press_position => move |_, _, x, y| {
+            press_position.set((x, y));
+        }));
+        click.connect_released(glib::clone!(@strong viewer => move |_, _, x, y| {
+            let (press_x, press_y) = press_position.get();
+            let moved = (x - press_x).hypot(y - press_y);
+            if viewer.btn_windows.is_active() && moved <= CLICK_MOVE_TOLERANCE {
+                viewer.toggle_window_at(x, y);
+            }
+        }));
+        area.add_controller(click);
+        btn_clear_windows.connect_clicked(glib::clone!(@strong viewer => move |_| {
+            viewer.edit_windows(|regions| regions.clear());
+        }));
+
+        viewer
+    }
+
+    /// Shows the analysis windows over the image and lets them be placed by clicking
+    fn enable_analysis_windows(
+        &self,
+        params: &ParametersState,
+        on_changed: Rc<dyn Fn(&[AnalysisRegion])>,
+    ) {
+        self.state.borrow_mut().windows = Some(AnalysisWindows {
+            regions: vec![],
+            window_size: params.analysis_window_size,
+            obj_detection_threshold: params.obj_detection_threshold,
+            center: None,
+            on_changed,
+        });
+        self.btn_windows.set_visible(true);
+        self.btn_clear_windows.set_visible(true);
+        self.set_analysis_windows(params);
+    }
+
+    fn set_analysis_windows(&self, params: &ParametersState) {
+        let mut state = self.state.borrow_mut();
+        if let Some(windows) = &mut state.windows {
+            windows.regions = params.analysis_regions.clone();
+            windows.window_size = params.analysis_window_size;
+            windows.obj_detection_threshold = params.obj_detection_threshold;
+        }
+        state.find_disk_center();
+        self.area.queue_draw();
+    }
+
+    /// Changes the analysis windows and passes them on to the change handler
+    fn edit_windows<F: FnOnce(&mut Vec<AnalysisRegion>)>(&self, edit: F) {
+        let (regions, on_changed) = {
+            let mut state = self.state.borrow_mut();
+            let windows = match &mut state.windows {
+                Some(windows) => windows,
+                None => return,
+            };
+            edit(&mut windows.regions);
+            (windows.regions.clone(), windows.on_changed.clone())
+        };
+        on_changed(&regions);
+        self.area.queue_draw();
+    }
+
+    /// Places an analysis window at a position in the view, or removes the one
+    /// already there
+    fn toggle_window_at(&self, x: f64, y: f64) {
+        let width = self.area.width() as f64;
+        let height = self.area.height() as f64;
+        let placement = {
+            let state = self.state.borrow();
+            match (state.view_to_image(x, y, width, height), &state.windows) {
+                (Some((image_x, image_y)), Some(windows)) => windows.center.map(|center| {
+                    (
+                        image_x as f64 - center.0,
+                        image_y as f64 - center.1,
+                        windows.window_size as f64 / 2.0,
+                    )
+                }),
+                _ => None,
+            }
+        };
+        let (offset_x, offset_y, half_size) = match placement {
+            Some(placement) => placement,
+            None => return,
+        };
+        self.edit_windows(|regions| {
+            match regions.iter().position(|r| {
+                (r.offset_x - offset_x).abs() <= half_size
+                    && (r.offset_y - offset_y).abs() <= half_size
+            }) {
+                Some(index) => {
+                    regions.remove(index);
+                }
+                None => regions.push(AnalysisRegion { offset_x, offset_y }),
+            }
+        });
+    }
+
+    fn set_zoom(&self, zoom: Zoom) {
+        let width = self.area.width() as f64;
+        let height = self.area.height() as f64;
+        let mut state = self.state.borrow_mut();
+
+        // Keep the center of the view where it is
+        let (scale, view_x, view_y) = state.transform(width, height);
+        let center_x = view_x + width / scale / 2.0;
+        let center_y = view_y + height / scale / 2.0;
+        if let Zoom::Scale(new_scale) = zoom {
+            state.view_x = center_x - width / new_scale / 2.0;
+            state.view_y = center_y - height / new_scale / 2.0;
+        }
+        state.zoom = zoom;
+        self.area.queue_draw();
+    }
+
+    /// The image coordinate at the center of the view
+    fn view_center(&self) -> Option<(usize, usize)> {
+        let width = self.area.width() as f64;
+        let height = self.area.height() as f64;
+        let state = self.state.borrow();
+        let image = state.image.as_ref()?;
+        let (scale, view_x, view_y) = state.transform(width, height);
+        let center_x = (view_x + width / scale / 2.0).clamp(0.0, (image.width - 1) as f64);
+        let center_y = (view_y + height / scale / 2.0).clamp(0.0, (image.height - 1) as f64);
+        Some((center_x as usize, center_y as usize))
+    }
+
+    fn update_readout(&self, x: f64, y: f64) {
+        let state = self.state.borrow();
+        let width = self.area.width() as f64;
+        let height = self.area.height() as f64;
+        let readout = match (state.view_to_image(x, y, width, height), &state.image) {
+            (Some((image_x, image_y)), Some(image)) => {
+                let values: Vec<String> = (0..image.num_bands())
+                    .map(|b| format!("{:.1}", image.get_band(b).get(image_x, image_y)))
+                    .collect();
+                format!(
+                    "x: {} y: {}  value: {}",
+                    image_x,
+                    image_y,
+                    values.join(", ")
+                )
+            }
+            _ => "".to_owned(),
+        };
+        self.lbl_readout.set_label(&readout);
+    }
+
+    fn stop_playback(&self) {
+        if let Some(source_id) = self.state.borrow_mut().playback.take() {
+            source_id.remove();
+        }
+        self.btn_play.set_icon_name("media-playback-start");
+    }
+
+    fn toggle_playback(&self) {
+        if self.state.borrow().playback.is_some() {
+            self.stop_playback();
+            return;
+        }
+
+        // Start again from the beginning if we're already at the end
+        let adjustment = self.scl_frame.adjustment();
+        if self.scl_frame.value() >= adjustment.upper() {
+            self.scl_frame.set_value(0.0);
+        }
+
+        let viewer = self.clone();
+        let source_id =
+            glib::timeout_add_local(Duration::from_millis(PLAYBACK_INTERVAL_MS), move || {
+                let next = viewer.scl_frame.value() + 1.0;
+                if next > viewer.scl_frame.adjustment().upper() {
+                    // The source is removed by returning false, so it mustn't be removed again
+                    viewer.state.borrow_mut().playback = None;
+                    viewer.btn_play.set_icon_name("media-playback-start");
+                    glib::Continue(false)
+                } else {
+                    viewer.scl_frame.set_value(next);
+                    glib::Continue(true)
+                }
+            });
+        self.state.borrow_mut().playback = Some(source_id);
+        self.btn_play.set_icon_name("media-playback-pause");
+    }
+
+    /// Loads and shows a frame of the current SER file
+    fn show_frame(&self, frame_index: usize) {
+        let frame = {
+            let mut state = self.state.borrow_mut();
+            if state.frame_index == frame_index && state.image.is_some() {
+                return;
+            }
+            if state.ser_file.is_none() {
+                if let Some(ser_path) = &state.ser_path {
+                    match SerFile::load_ser(ser_path.to_str().unwrap()) {
+                        Ok(ser_file) => state.ser_file = Some(ser_file),
+                        Err(why) => error!("Failed to load ser file {:?}: {:?}", ser_path, why),
+                    }
+                }
+            }
+            let ser_file = match &state.ser_file {
+                Some(ser_file) => ser_file,
+                None => return,
+            };
+            match ser_file.get_frame(frame_index) {
+                Ok(frame) => frame,
+                Err(why) => {
+                    error!("Failed to load frame {}: {:?}", frame_index, why);
+                    return;
+                }
+            }
+        };
+        self.show_ser_frame(frame_index, &frame);
+    }
+
+    fn show_ser_frame(&self, frame_index: usize, frame: &SerFrame) {
+        self.set_image(&frame.buffer);
+        self.state.borrow_mut().frame_index = frame_index;
+        self.lbl_frame.set_label(&format!(
+            "Frame {} / {}  {}",
+            frame_index + 1,
+            self.state.borrow().frame_count,
+            frame.timestamp.format("%Y-%m-%d %H:%M:%S%.3f UTC")
+        ));
+    }
+
+    /// Shows a frame from a SER file and enables the scrubber to browse the rest
+    pub fn set_ser_frame(&self, ser_file_path: &Path, frame_count: usize, frame: &SerFrame) {
+        self.stop_playback();
+        {
+            let mut state = self.state.borrow_mut();
+            state.ser_path = Some(ser_file_path.to_owned());
+            state.ser_file = None;
+            state.frame_count = frame_count;
+        }
+        // The frame is shown before moving the slider so it doesn't load it again
+        self.show_ser_frame(0, frame);
+        self.scl_frame.set_value(0.0);
+        self.scl_frame
+            .set_range(0.0, (frame_count.max(2) - 1) as f64);
+        self.scrubber.set_visible(frame_count > 1);
+    }
+
+    /// Shows a single image, such as a master frame or the output of the threshold test
+    pub fn set_single_image(&self, image: &Image) {
+        self.stop_playback();
+        {
+            let mut state = self.state.borrow_mut();
+            state.ser_path = None;
+            state.ser_file = None;
+        }
+        self.scrubber.set_visible(false);
+        self.set_image(image);
+    }
+
+    fn set_image(&self, image: &Image) {
+        let pixbuf = match image_to_picture(image) {
+            Ok(pixbuf) => pixbuf,
+            Err(why) => {
+                error!("Failed to convert image for preview: {:?}", why);
+                return;
+            }
+        };
+        let mut state = self.state.borrow_mut();
+        state.image = Some(image.clone());
+        state.pixbuf = Some(pixbuf);
+        state.find_disk_center();
+        self.area.queue_draw();
+    }
+}
+
+/// Creates a viewer inside the placeholder box with id `preview_id`
+pub fn attach_viewer(builder: &Builder, preview_id: &str) {
+    let container: gtk::Box = builder
+        .object(preview_id)
+        .unwrap_or_else(|| panic!("Failed to bind object with id '{}'", preview_id));
+    let viewer = PreviewViewer::new(&container);
+    VIEWERS.with(|v| v.borrow_mut().insert(preview_id.to_owned(), viewer));
+}
+
+/// Shows an image in the viewer attached with `preview_id`
+pub fn set_preview_image(preview_id: &str, image: &Image) {
+    if let Some(viewer) = get_viewer(preview_id) {
+        viewer.set_single_image(image);
+    }
+}
+
+/// Shows the first frame of a SER file in the viewer attached with `preview_id`,
+/// allowing the rest of the frames to be browsed
+pub fn set_preview_ser_frame(
+    preview_id: &str,
+    ser_file_path: &Path,
+    frame_count: usize,
+    frame: &SerFrame,
+) {
+    if let Some(viewer) = get_viewer(preview_id) {
+        viewer.set_ser_frame(ser_file_path, frame_count, frame);
+    }
+}
+
+/// The image coordinate at the center of the viewer attached with `preview_id`, which
+/// is where the user is currently looking
+pub fn preview_view_center(preview_id: &str) -> Option<(usize, usize)> {
+    get_viewer(preview_id).and_then(|viewer| viewer.view_center())
+}
+
+/// Shows the analysis windows from `params` over the viewer attached with `preview_id`,
+/// and lets the user place them by clicking. `on_changed` is called with the windows
+/// each time one is placed or removed.
+pub fn enable_analysis_windows<F: Fn(&[AnalysisRegion]) + 'static>(
+    preview_id: &str,
+    params: &ParametersState,
+    on_changed: F,
+) {
+    if let Some(viewer) = get_viewer(preview_id) {
+        viewer.enable_analysis_windows(params, Rc::new(on_changed));
+    }
+}
+
+/// Updates the analysis windows shown by the viewer attached with `preview_id`, such
+/// as after the window size or object detection threshold has changed
+pub fn set_analysis_windows(preview_id: &str, params: &ParametersState) {
+    if let Some(viewer) = get_viewer(preview_id) {
+        viewer.set_analysis_windows(params);
+    }
+}
+
+fn get_viewer(preview_id: &str) -> Option<PreviewViewer> {
+    let viewer = VIEWERS.with(|v| v.borrow().get(preview_id).cloned());
+    if viewer.is_none() {
+        error!("No preview viewer with id '{}'", preview_id);
+    }
+    viewer
+}